@@ -0,0 +1,203 @@
+use crate::{Packet, PacketParsingError, PACKET_SEPARATOR};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A `PayloadDecoder` parses a V4 payload that arrives in chunks, e.g. a streamed HTTP body.
+/// Packets are yielded as soon as their separator is seen, and a trailing partial packet
+/// is kept buffered until the next chunk or `finish`.
+#[derive(Debug)]
+pub struct PayloadDecoder {
+    buffer: String,
+    index: usize,
+    len: usize,
+    limit: usize,
+}
+
+impl Default for PayloadDecoder {
+    fn default() -> Self {
+        PayloadDecoder::new()
+    }
+}
+
+/// The error of `PayloadDecoder::feed`, along with the packets of the chunk that were
+/// decoded before it
+#[derive(Debug, Eq, PartialEq)]
+pub struct FeedError {
+    pub packets: Vec<Packet>,
+    pub error: PacketParsingError,
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FeedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl PayloadDecoder {
+    pub fn new() -> PayloadDecoder {
+        PayloadDecoder::with_limit(usize::MAX)
+    }
+
+    /// Same as `PayloadDecoder::new`, but like `Payload::try_from_with_limit`, rejects a
+    /// payload longer than `limit` bytes, without buffering the chunk that exceeds it
+    pub fn with_limit(limit: usize) -> PayloadDecoder {
+        PayloadDecoder {
+            buffer: String::new(),
+            index: 0,
+            len: 0,
+            limit,
+        }
+    }
+
+    /// Feeds a chunk of the payload, returning the packets completed by it.
+    /// The packets after an invalid one stay buffered and are returned by the next call.
+    pub fn feed(&mut self, chunk: &str) -> Result<Vec<Packet>, FeedError> {
+        self.len = self.len.saturating_add(chunk.len());
+        if self.len > self.limit {
+            return Err(FeedError {
+                packets: Vec::new(),
+                error: PacketParsingError::PayloadTooLarge(self.len),
+            });
+        }
+        self.buffer.push_str(chunk);
+        let mut packets = Vec::new();
+        while let Some(i) = self.buffer.find(PACKET_SEPARATOR) {
+            let packet = Packet::try_from(&self.buffer[..i]).map_err(|e| e.in_payload(self.index));
+            self.buffer.drain(..i + PACKET_SEPARATOR.len());
+            self.index += 1;
+            match packet {
+                Ok(packet) => packets.push(packet),
+                Err(error) => return Err(FeedError { packets, error }),
+            }
+        }
+        Ok(packets)
+    }
+
+    /// Parses the last packet of the payload, which has no trailing separator
+    pub fn finish(self) -> Result<Packet, PacketParsingError> {
+        if self.len > self.limit {
+            return Err(PacketParsingError::PayloadTooLarge(self.len));
+        }
+        if self.index == 0 && self.buffer.is_empty() {
+            return Err(PacketParsingError::EmptyString);
+        }
+        Packet::try_from(self.buffer.as_str()).map_err(|e| e.in_payload(self.index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Payload;
+
+    fn decode_chunks(chunks: &[&str]) -> Result<Vec<Packet>, PacketParsingError> {
+        let mut decoder = PayloadDecoder::new();
+        let mut packets = Vec::new();
+        for chunk in chunks {
+            packets.extend(decoder.feed(chunk).map_err(|e| e.error)?);
+        }
+        packets.push(decoder.finish()?);
+        Ok(packets)
+    }
+
+    #[test]
+    fn chunked_payload_matches_one_shot() {
+        let payload_msg = "4hello\x1ebAQID\x1e4wörld\x1e2probe";
+        let expected = Payload::try_from(payload_msg).unwrap();
+        for (i, _) in payload_msg.char_indices().skip(1) {
+            for (j, _) in payload_msg[i..].char_indices().skip(1) {
+                let chunks = [
+                    &payload_msg[..i],
+                    &payload_msg[i..i + j],
+                    &payload_msg[i + j..],
+                ];
+                assert_eq!(expected.packets(), decode_chunks(&chunks).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn partial_packet_is_buffered() {
+        let mut decoder = PayloadDecoder::new();
+        assert_eq!(Ok(vec![]), decoder.feed("4hel"));
+        assert_eq!(
+            Ok(vec![Packet::try_from("4hello").unwrap()]),
+            decoder.feed("lo\x1e4wor")
+        );
+        assert_eq!(Ok(vec![]), decoder.feed("ld"));
+        assert_eq!(Ok(Packet::try_from("4world").unwrap()), decoder.finish());
+    }
+
+    #[test]
+    fn blank_packet_in_chunks() {
+        assert_eq!(
+            Err(PacketParsingError::EmptySegment(1)),
+            decode_chunks(&["4hello\x1e", "\x1e4world"])
+        );
+        assert_eq!(Err(PacketParsingError::EmptyString), decode_chunks(&[""]));
+    }
+
+    #[test]
+    fn packets_before_error_are_returned() {
+        let mut decoder = PayloadDecoder::new();
+        assert_eq!(
+            Err(FeedError {
+                packets: vec![Packet::try_from("4hello").unwrap()],
+                error: PacketParsingError::InvalidPayloadPacket {
+                    index: 1,
+                    source: Box::new(PacketParsingError::InvalidChar),
+                },
+            }),
+            decoder.feed("4hello\x1e9\x1e4world\x1e")
+        );
+        // the packets after the invalid one are not lost either
+        assert_eq!(
+            Ok(vec![Packet::try_from("4world").unwrap()]),
+            decoder.feed("2")
+        );
+        assert_eq!(Ok(Packet::try_from("2").unwrap()), decoder.finish());
+    }
+
+    #[test]
+    fn payload_over_limit() {
+        let payload_msg = "4hello\x1e4world";
+        let limit = payload_msg.len();
+        assert_eq!(
+            Payload::try_from(payload_msg).unwrap().packets(),
+            {
+                let mut decoder = PayloadDecoder::with_limit(limit);
+                let mut packets = decoder.feed(&payload_msg[..8]).unwrap();
+                packets.extend(decoder.feed(&payload_msg[8..]).unwrap());
+                packets.push(decoder.finish().unwrap());
+                packets
+            }
+            .as_slice()
+        );
+
+        let mut decoder = PayloadDecoder::with_limit(limit - 1);
+        assert_eq!(
+            Ok(vec![Packet::try_from("4hello").unwrap()]),
+            decoder.feed(&payload_msg[..8])
+        );
+        // the chunk crossing the limit is rejected without being buffered
+        assert_eq!(
+            Err(FeedError {
+                packets: vec![],
+                error: PacketParsingError::PayloadTooLarge(limit),
+            }),
+            decoder.feed(&payload_msg[8..])
+        );
+        assert_eq!(
+            Err(PacketParsingError::PayloadTooLarge(limit)),
+            decoder.finish()
+        );
+    }
+}
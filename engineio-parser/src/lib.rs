@@ -1,29 +1,297 @@
-use base64::DecodeError;
-use thiserror::Error;
+//! Without the default `std` feature, the parser only depends on `alloc`,
+//! and the errors implement `Display` without implementing `std::error::Error`.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-const PACKET_SEPARATOR: &str = "\x1e";
+extern crate alloc;
+
+mod decoder;
+#[cfg(feature = "json")]
+mod parsed;
+#[cfg(feature = "socketio")]
+pub mod socketio;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use decoder::*;
+#[cfg(feature = "json")]
+pub use parsed::*;
+#[cfg(feature = "wasm")]
+pub use wasm::*;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use core::fmt;
+use core::str::{FromStr, Utf8Error};
+
+/// The record separator between packets in a V4 payload
+pub const PACKET_SEPARATOR: &str = "\x1e";
 const PACKET_PROBE: &str = "probe";
+/// Binary message packets start with a 'b' instead of the digit of the message type
+const BINARY_MESSAGE_CHAR: char = 'b';
 
-#[derive(Error, Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum PacketParsingError {
-    #[error("invalid char")]
     InvalidChar,
-    #[error("Invalid packet length")]
     InvalidPacketLen,
-    #[error("Emtpy string")]
+    /// The whole input was empty
     EmptyString,
-    #[error("Invalid Binary Message")]
+    /// A blank record at the given index of an otherwise non-empty payload
+    EmptySegment(usize),
     InvalidBinaryMessage,
     /// An invalid ping occurs when we are using the XHR transport and we get anything else besides '2probe'
-    #[error("invalid ping packet")]
     InvalidPing,
     /// An invalid pong occurs when we are using the XHR transport and we get anything else besides '3probe'
-    #[error("invalid pong packet")]
     InvalidPong,
+    /// A message packet must not contain the record separator, otherwise it would corrupt a payload
+    SeparatorInMessage,
+    /// The V4 protocol defines the close packet without any data, so trailing content is rejected
+    InvalidClose,
+    /// The upgrade packet carries no data, so trailing content is rejected
+    InvalidUpgrade,
+    /// The noop packet carries no data, so trailing content is rejected
+    InvalidNoop,
+    /// The open packet does not carry a valid handshake json
+    InvalidHandshake,
+    /// The textual content of a packet given as bytes is not valid UTF-8
+    NotUtf8,
+    /// The payload is longer than the `maxPayload` advertised to the client
+    PayloadTooLarge(usize),
+    /// An Open packet that is not the first packet of the payload, at the given index
+    UnexpectedOpen(usize),
+    /// A packet within a payload failed to parse, `index` being its zero-based position in the payload
+    InvalidPayloadPacket {
+        index: usize,
+        source: Box<PacketParsingError>,
+    },
+}
+
+impl PacketParsingError {
+    fn in_payload(self, index: usize) -> PacketParsingError {
+        match self {
+            PacketParsingError::EmptyString => PacketParsingError::EmptySegment(index),
+            err => PacketParsingError::InvalidPayloadPacket {
+                index,
+                source: Box::new(err),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum PacketEncodingError {
+    /// A string packet containing the record separator cannot be represented in a textual payload
+    SeparatorInPacket(usize),
+}
+
+impl fmt::Display for PacketParsingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketParsingError::InvalidChar => write!(f, "invalid char"),
+            PacketParsingError::InvalidPacketLen => write!(f, "Invalid packet length"),
+            PacketParsingError::EmptyString => write!(f, "Emtpy string"),
+            PacketParsingError::EmptySegment(index) => {
+                write!(f, "empty packet at index {} of payload", index)
+            }
+            PacketParsingError::InvalidBinaryMessage => write!(f, "Invalid Binary Message"),
+            PacketParsingError::InvalidPing => write!(f, "invalid ping packet"),
+            PacketParsingError::InvalidPong => write!(f, "invalid pong packet"),
+            PacketParsingError::SeparatorInMessage => {
+                write!(f, "message packet contains the record separator")
+            }
+            PacketParsingError::InvalidClose => write!(f, "close packet with data"),
+            PacketParsingError::InvalidUpgrade => write!(f, "upgrade packet with data"),
+            PacketParsingError::InvalidNoop => write!(f, "noop packet with data"),
+            PacketParsingError::InvalidHandshake => write!(f, "invalid handshake"),
+            PacketParsingError::NotUtf8 => write!(f, "packet is not valid UTF-8"),
+            PacketParsingError::PayloadTooLarge(len) => {
+                write!(f, "payload of {} bytes exceeds the limit", len)
+            }
+            PacketParsingError::UnexpectedOpen(index) => {
+                write!(f, "unexpected open packet at index {} of payload", index)
+            }
+            PacketParsingError::InvalidPayloadPacket { index, .. } => {
+                write!(f, "invalid packet at index {} of payload", index)
+            }
+        }
+    }
+}
+
+/// Not derived, since the derived source of `InvalidPayloadPacket` would be the `Box` itself,
+/// which cannot be downcast to the `PacketParsingError` of the packet
+#[cfg(feature = "std")]
+impl std::error::Error for PacketParsingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PacketParsingError::InvalidPayloadPacket { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PacketEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketEncodingError::SeparatorInPacket(index) => {
+                write!(f, "packet {} contains the record separator", index)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PacketEncodingError {}
+
+/// engine.io encodes binary data with the standard base64 alphabet, with padding
+fn encode_base64<T: AsRef<[u8]>>(input: T) -> String {
+    STANDARD.encode(input)
+}
+
+fn decode_base64<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, base64::DecodeError> {
+    STANDARD.decode(input)
+}
+
+/// Some clients encode binary data with the url-safe alphabet ('-' and '_'), with or without padding
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decodes the standard base64, falling back to the url-safe alphabet if `lenient`
+fn decode_base64_lenient(input: &[u8], lenient: bool) -> Result<Vec<u8>, base64::DecodeError> {
+    match decode_base64(input) {
+        Err(_) if lenient => URL_SAFE_LENIENT.decode(input),
+        decoded => decoded,
+    }
+}
+
+/// Binary packet data is serialized as a base64 string
+#[cfg(feature = "serde")]
+mod serde_base64 {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::encode_base64(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        super::decode_base64(encoded).map_err(de::Error::custom)
+    }
+}
+
+/// The version of the engine.io protocol, which determines how payloads are framed.
+/// V4 separates packets with the record separator, while V3 prefixes each packet with its length.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum ProtocolVersion {
+    V3,
+    #[default]
+    V4,
+}
+
+/// How strictly the content of ping and pong packets is checked. Strict parsing only accepts
+/// no content or "probe", while lenient parsing first trims the whitespace around the content.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum Strictness {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+/// The `ParserConfig` parameterizes the framing of payloads, e.g. for protocol experiments
+/// with another record separator than the one mandated by V4. The default follows V4.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ParserConfig {
+    /// The separator between the packets of a payload, which messages must not contain
+    pub separator: char,
+    pub strictness: Strictness,
+    /// Whether binary packets encoded with the url-safe base64 alphabet are accepted too.
+    /// Off by default, since engine.io mandates the standard alphabet.
+    pub lenient_base64: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            separator: '\x1e',
+            strictness: Strictness::default(),
+            lenient_base64: false,
+        }
+    }
+}
+
+/// The transport a packet is written to, which determines how binary data is encoded.
+/// Polling writes it as base64, while websocket sends the raw bytes in a binary frame.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum TransportKind {
+    Polling,
+    Websocket,
+}
+
+impl TransportKind {
+    /// Parses the `transport` query parameter sent by the client, e.g. "polling"
+    pub fn from_name(name: &str) -> Option<TransportKind> {
+        match name {
+            "polling" => Some(TransportKind::Polling),
+            "websocket" => Some(TransportKind::Websocket),
+            _ => None,
+        }
+    }
+
+    /// The name of the transport in the `transport` query parameter and the handshake upgrades
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransportKind::Polling => "polling",
+            TransportKind::Websocket => "websocket",
+        }
+    }
 }
 
 /// Packet type can one of enumerations
+///
+/// The enum is non-exhaustive, as later protocol versions may add packet types,
+/// so a match outside of this crate needs a wildcard arm:
+///
+/// ```
+/// use engineio_parser::PacketType;
+///
+/// fn is_control(packet_type: &PacketType) -> bool {
+///     match packet_type {
+///         PacketType::Message => false,
+///         _ => true,
+///     }
+/// }
+/// assert!(is_control(&PacketType::Ping));
+/// ```
+///
+/// Listing every current packet type is not enough:
+///
+/// ```compile_fail
+/// use engineio_parser::PacketType;
+///
+/// fn digit(packet_type: &PacketType) -> u8 {
+///     match packet_type {
+///         PacketType::Open => 0,
+///         PacketType::Close => 1,
+///         PacketType::Ping => 2,
+///         PacketType::Pong => 3,
+///         PacketType::Message => 4,
+///         PacketType::Upgrade => 5,
+///         PacketType::Noop => 6,
+///     }
+/// }
+/// ```
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum PacketType {
     Open,
     Close,
@@ -34,126 +302,664 @@ pub enum PacketType {
     Noop,
 }
 
+impl PacketType {
+    /// Maps the digit at the start of an encoded packet to its type
+    pub fn from_char(ch: char) -> Option<PacketType> {
+        match ch {
+            '0' => Some(PacketType::Open),
+            '1' => Some(PacketType::Close),
+            '2' => Some(PacketType::Ping),
+            '3' => Some(PacketType::Pong),
+            '4' => Some(PacketType::Message),
+            '5' => Some(PacketType::Upgrade),
+            '6' => Some(PacketType::Noop),
+            _ => None,
+        }
+    }
+
+    /// The digit the packet type is encoded as
+    pub fn as_char(&self) -> char {
+        match self {
+            PacketType::Open => '0',
+            PacketType::Close => '1',
+            PacketType::Ping => '2',
+            PacketType::Pong => '3',
+            PacketType::Message => '4',
+            PacketType::Upgrade => '5',
+            PacketType::Noop => '6',
+        }
+    }
+}
+
+/// Reads the type of an encoded packet from its first char only, without parsing or allocating
+/// the rest of it, e.g. to answer heartbeats cheaply. A binary message starting with 'b' is a
+/// Message. The rest of the packet is not validated, so a full parse may still fail.
+pub fn peek_packet_type(s: &str) -> Option<PacketType> {
+    match s.chars().next()? {
+        BINARY_MESSAGE_CHAR => Some(PacketType::Message),
+        ch => PacketType::from_char(ch),
+    }
+}
+
 /// Packet data can be UTF-8 string or binary data
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum PacketData {
     String(String),
+    #[cfg_attr(feature = "serde", serde(with = "serde_base64"))]
     Binary(Vec<u8>),
 }
 
+impl PacketData {
+    /// The string data, or `None` for binary data
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PacketData::String(s) => Some(s),
+            PacketData::Binary(_) => None,
+        }
+    }
+
+    /// The data as text, which for binary data only succeeds if its bytes are valid UTF-8
+    pub fn try_as_str(&self) -> Result<&str, Utf8Error> {
+        match self {
+            PacketData::String(s) => Ok(s),
+            PacketData::Binary(bytes) => core::str::from_utf8(bytes),
+        }
+    }
+
+    /// The raw bytes of the data, i.e. the UTF-8 bytes of string data
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            PacketData::String(s) => s.as_bytes(),
+            PacketData::Binary(b) => b,
+        }
+    }
+
+    /// The length of the data in bytes
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 /// A packet has a packet type, and some optional data
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     packet_type: PacketType,
     data: Option<PacketData>,
 }
 
 impl Packet {
-    pub fn get_packet_type(&self) -> PacketType {
-        self.packet_type.clone()
+    pub fn new(packet_type: PacketType, data: Option<PacketData>) -> Packet {
+        Packet { packet_type, data }
+    }
+    /// An open packet carrying the handshake json
+    pub fn open(handshake: impl Into<String>) -> Packet {
+        Packet::new(PacketType::Open, Some(PacketData::String(handshake.into())))
+    }
+    pub fn close() -> Packet {
+        Packet::new(PacketType::Close, None)
     }
-    pub fn get_packet_data(&self) -> Option<&PacketData> {
+    /// A heartbeat ping packet, i.e. "2"
+    pub fn ping() -> Packet {
+        Packet::new(PacketType::Ping, None)
+    }
+    /// A heartbeat pong packet, i.e. "3"
+    pub fn pong() -> Packet {
+        Packet::new(PacketType::Pong, None)
+    }
+    /// The ping packet sent by the client to probe the websocket during an upgrade, i.e. "2probe"
+    pub fn ping_probe() -> Packet {
+        Packet::new(
+            PacketType::Ping,
+            Some(PacketData::String(PACKET_PROBE.to_string())),
+        )
+    }
+    /// The pong packet sent by the server in response to a probe, i.e. "3probe"
+    pub fn pong_probe() -> Packet {
+        Packet::new(
+            PacketType::Pong,
+            Some(PacketData::String(PACKET_PROBE.to_string())),
+        )
+    }
+    pub fn message_text(msg: impl Into<String>) -> Packet {
+        Packet::new(PacketType::Message, Some(PacketData::String(msg.into())))
+    }
+    pub fn message_binary(bytes: Vec<u8>) -> Packet {
+        Packet::new(PacketType::Message, Some(PacketData::Binary(bytes)))
+    }
+    pub fn upgrade() -> Packet {
+        Packet::new(PacketType::Upgrade, None)
+    }
+    pub fn noop() -> Packet {
+        Packet::new(PacketType::Noop, None)
+    }
+    pub fn packet_type(&self) -> &PacketType {
+        &self.packet_type
+    }
+    pub fn data(&self) -> Option<&PacketData> {
         self.data.as_ref()
     }
+
+    /// Whether the packet is a ping or pong probing the websocket during the upgrade,
+    /// i.e. "2probe" or "3probe"
+    pub fn is_probe(&self) -> bool {
+        matches!(self.packet_type, PacketType::Ping | PacketType::Pong)
+            && matches!(&self.data, Some(PacketData::String(probe)) if probe == PACKET_PROBE)
+    }
+    /// Whether the packet is a message with empty text, i.e. "4"
+    pub fn is_empty_message(&self) -> bool {
+        self.packet_type == PacketType::Message
+            && matches!(&self.data, Some(PacketData::String(msg)) if msg.is_empty())
+    }
+    /// Whether the packet is a bare ping "2", sent by the server to check the connection
+    pub fn is_heartbeat_ping(&self) -> bool {
+        self.packet_type == PacketType::Ping && self.data.is_none()
+    }
+    /// Whether the packet is a bare pong "3", sent by the client in response to a heartbeat ping
+    pub fn is_heartbeat_pong(&self) -> bool {
+        self.packet_type == PacketType::Pong && self.data.is_none()
+    }
+
+    /// The length in bytes of the encoded packet on the transport, without encoding it
+    pub fn byte_len(&self, transport: TransportKind) -> usize {
+        match (&self.data, transport) {
+            // the 'b' prefix, followed by the padded base64 of the bytes
+            (Some(PacketData::Binary(bytes)), TransportKind::Polling) => {
+                1 + bytes.len().div_ceil(3) * 4
+            }
+            (Some(PacketData::Binary(bytes)), TransportKind::Websocket) => bytes.len(),
+            (Some(PacketData::String(msg)), _) => 1 + msg.len(),
+            (None, _) => 1,
+        }
+    }
+
+    /// A cheap 64-bit FNV-1a hash of the type and data of the packet, e.g. for idempotency
+    /// checks in a responder. Equal packets hash equal, and a string and binary data with
+    /// the same bytes hash differently. It is not a cryptographic hash, so it must not be
+    /// relied upon against clients crafting collisions.
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+        let (tag, data): (u8, &[u8]) = match &self.data {
+            None => (0, &[]),
+            Some(PacketData::String(msg)) => (1, msg.as_bytes()),
+            Some(PacketData::Binary(bytes)) => (2, bytes),
+        };
+        [self.packet_type.as_char() as u8, tag]
+            .iter()
+            .chain(data)
+            .fold(FNV_OFFSET, |hash, byte| {
+                (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
 }
 
 impl TryFrom<&str> for Packet {
     type Error = PacketParsingError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value.len() < 1 {
+        Packet::try_from_with_limit(value, usize::MAX)
+    }
+}
+
+impl FromStr for Packet {
+    type Err = PacketParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Packet::try_from(s)
+    }
+}
+
+impl Packet {
+    /// Decodes the base64 following the 'b' of a binary packet, rejecting it if longer than
+    /// `limit` bytes. The base64 is decoded from the slice itself, without copying it first.
+    /// If `lenient`, the url-safe alphabet is tried when the standard one fails.
+    fn binary_from_base64(
+        encoded: &[u8],
+        limit: usize,
+        lenient: bool,
+    ) -> Result<Packet, PacketParsingError> {
+        if encoded.len() > limit {
+            return Err(PacketParsingError::InvalidBinaryMessage);
+        }
+        match decode_base64_lenient(encoded, lenient) {
+            Ok(b) => Ok(Packet {
+                packet_type: PacketType::Message,
+                data: Some(PacketData::Binary(b)),
+            }),
+            Err(_) => Err(PacketParsingError::InvalidBinaryMessage),
+        }
+    }
+
+    /// Parses a packet framed by its length in a V3 payload, whose message may contain
+    /// the record separator
+    fn parse_v3(value: &str) -> Result<Packet, PacketParsingError> {
+        match value.strip_prefix(PacketType::Message.as_char()) {
+            Some(msg) => Ok(Packet::message_text(msg)),
+            None => Packet::try_from(value),
+        }
+    }
+
+    /// Same as `Packet::try_from`, but a binary packet whose base64 data is longer than
+    /// `limit` bytes is rejected with `InvalidBinaryMessage` before anything is decoded
+    pub fn try_from_with_limit(value: &str, limit: usize) -> Result<Packet, PacketParsingError> {
+        Packet::parse(value, limit, &ParserConfig::default())
+    }
+
+    /// Same as `Packet::try_from`, checking the content of ping and pong packets as given
+    pub fn try_from_with_strictness(
+        value: &str,
+        strictness: Strictness,
+    ) -> Result<Packet, PacketParsingError> {
+        let config = ParserConfig {
+            strictness,
+            ..ParserConfig::default()
+        };
+        Packet::parse(value, usize::MAX, &config)
+    }
+
+    fn parse(
+        value: &str,
+        limit: usize,
+        config: &ParserConfig,
+    ) -> Result<Packet, PacketParsingError> {
+        if value.is_empty() {
             return Err(PacketParsingError::EmptyString);
         }
         let mut chars = value.chars();
-        if let Some(ch) = chars.next() {
-            match ch {
-                '0' => Ok(Packet {
-                    packet_type: PacketType::Open,
-                    data: None,
-                }),
-                '1' => Ok(Packet {
-                    packet_type: PacketType::Close,
-                    data: None,
-                }),
-                '2' => {
-                    let msg = chars.collect::<String>();
-                    if msg.len() > 0 && msg != PACKET_PROBE {
-                        Err(PacketParsingError::InvalidPing)
+        let ch = chars.next().ok_or(PacketParsingError::InvalidChar)?;
+        if ch == BINARY_MESSAGE_CHAR {
+            return Packet::binary_from_base64(
+                chars.as_str().as_bytes(),
+                limit,
+                config.lenient_base64,
+            );
+        }
+        let packet_type = PacketType::from_char(ch).ok_or(PacketParsingError::InvalidChar)?;
+        match packet_type {
+            // the open packet sent by the server carries the handshake json
+            PacketType::Open => {
+                let handshake = chars.as_str();
+                Ok(Packet {
+                    packet_type,
+                    data: if handshake.is_empty() {
+                        None
                     } else {
-                        Ok(Packet {
-                            packet_type: PacketType::Ping,
-                            data: Some(PacketData::String(msg)),
-                        })
-                    }
+                        Some(PacketData::String(handshake.to_string()))
+                    },
+                })
+            }
+            PacketType::Ping | PacketType::Pong => {
+                let msg = match config.strictness {
+                    Strictness::Strict => chars.as_str(),
+                    Strictness::Lenient => chars.as_str().trim(),
+                };
+                if msg.is_empty() {
+                    Ok(Packet {
+                        packet_type,
+                        data: None,
+                    })
+                } else if msg == PACKET_PROBE {
+                    Ok(Packet {
+                        packet_type,
+                        data: Some(PacketData::String(msg.to_string())),
+                    })
+                } else if packet_type == PacketType::Ping {
+                    Err(PacketParsingError::InvalidPing)
+                } else {
+                    Err(PacketParsingError::InvalidPong)
                 }
-                '3' => {
-                    let msg = chars.collect::<String>();
-                    if msg.len() > 0 && msg != PACKET_PROBE {
-                        Err(PacketParsingError::InvalidPong)
-                    } else {
-                        Ok(Packet {
-                            packet_type: PacketType::Pong,
-                            data: Some(PacketData::String(msg)),
-                        })
-                    }
+            }
+            // a bare "4" is an empty message, e.g. a keepalive sent by socket.io,
+            // so it carries an empty string rather than no data. The text is checked
+            // on the input slice, then copied at once.
+            PacketType::Message => {
+                let msg = chars.as_str();
+                // a message containing the separator could not be re-serialized into a payload
+                if msg.contains(config.separator) {
+                    Err(PacketParsingError::SeparatorInMessage)
+                } else {
+                    Ok(Packet {
+                        packet_type,
+                        data: Some(PacketData::String(msg.to_string())),
+                    })
                 }
-                '4' => Ok(Packet {
-                    packet_type: PacketType::Message,
-                    data: Some(PacketData::String(chars.collect::<String>())),
-                }),
-                'b' => {
-                    let bytes = chars.collect::<String>().into_bytes();
-                    match base64::decode(bytes) {
-                        Ok(b) => Ok(Packet {
-                            packet_type: PacketType::Message,
-                            data: Some(PacketData::Binary(b)),
-                        }),
-                        Err(_) => Err(PacketParsingError::InvalidBinaryMessage),
-                    }
+            }
+            // the V4 protocol defines these packets without data, e.g. no reason after
+            // the close packet, so trailing content is rejected rather than silently dropped
+            PacketType::Close | PacketType::Upgrade | PacketType::Noop => {
+                if chars.as_str().is_empty() {
+                    Ok(Packet {
+                        packet_type,
+                        data: None,
+                    })
+                } else if packet_type == PacketType::Close {
+                    Err(PacketParsingError::InvalidClose)
+                } else if packet_type == PacketType::Upgrade {
+                    Err(PacketParsingError::InvalidUpgrade)
+                } else {
+                    Err(PacketParsingError::InvalidNoop)
                 }
-                '5' => Ok(Packet {
-                    packet_type: PacketType::Upgrade,
-                    data: None,
-                }),
-                '6' => Ok(Packet {
-                    packet_type: PacketType::Noop,
-                    data: None,
-                }),
-                _ => Err(PacketParsingError::InvalidChar),
             }
-        } else {
-            Err(PacketParsingError::InvalidChar)
+        }
+    }
+}
+
+/// Encodes a packet into its wire format, the inverse of `Packet::try_from`.
+/// Binary message data is written as a 'b' followed by the base64 of the bytes.
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let type_char = self.packet_type.as_char();
+        match &self.data {
+            Some(PacketData::String(msg)) => write!(f, "{}{}", type_char, msg),
+            Some(PacketData::Binary(bytes)) => {
+                write!(f, "{}{}", BINARY_MESSAGE_CHAR, encode_base64(bytes))
+            }
+            None => write!(f, "{}", type_char),
         }
     }
 }
 
 /// A payload is composed of one or more packets
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Payload {
     packets: Vec<Packet>,
 }
 
 impl Payload {
+    /// A payload of a single packet, e.g. the content of a websocket frame
+    pub fn single(packet: Packet) -> Payload {
+        Payload {
+            packets: alloc::vec![packet],
+        }
+    }
+    pub fn from_packets(packets: Vec<Packet>) -> Payload {
+        Payload { packets }
+    }
+    pub fn push(&mut self, packet: Packet) {
+        self.packets.push(packet);
+    }
     pub fn len(&self) -> usize {
         self.packets.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
     pub fn packets(&self) -> &[Packet] {
         self.packets.as_slice()
     }
-}
+    pub fn iter(&self) -> core::slice::Iter<'_, Packet> {
+        self.packets.iter()
+    }
 
-impl TryFrom<&str> for Payload {
-    type Error = PacketParsingError;
+    /// Encodes the payload into the textual format written by the polling transport,
+    /// with each encoded packet joined by the record separator
+    pub fn encode(&self) -> String {
+        self.encode_with_config(&ParserConfig::default())
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
+    /// Same as `encode`, but joins the packets with the separator of the config
+    pub fn encode_with_config(&self, config: &ParserConfig) -> String {
+        let mut separator = [0; 4];
+        self.packets
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(config.separator.encode_utf8(&mut separator))
+    }
+
+    /// Checks that the payload holds at most one Open packet, and that it is the first packet,
+    /// returning `UnexpectedOpen` with the index of the offending packet otherwise
+    pub fn validate(&self) -> Result<(), PacketParsingError> {
+        match self
+            .packets
+            .iter()
+            .skip(1)
+            .position(|p| p.packet_type == PacketType::Open)
+        {
+            Some(i) => Err(PacketParsingError::UnexpectedOpen(i + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `encode`, but fails if a packet could not be decoded back from the payload,
+    /// i.e. its string data contains the record separator
+    pub fn try_encode(&self) -> Result<String, PacketEncodingError> {
+        for (i, p) in self.packets.iter().enumerate() {
+            if let Some(PacketData::String(msg)) = &p.data {
+                if msg.contains(PACKET_SEPARATOR) {
+                    return Err(PacketEncodingError::SeparatorInPacket(i));
+                }
+            }
+        }
+        Ok(self.encode())
+    }
+
+    /// Same as `Payload::try_from`, but rejects an input longer than `limit` bytes
+    /// before parsing any packet
+    pub fn try_from_with_limit(value: &str, limit: usize) -> Result<Payload, PacketParsingError> {
+        if value.len() > limit {
+            return Err(PacketParsingError::PayloadTooLarge(value.len()));
+        }
+        Payload::try_from(value)
+    }
+
+    /// Same as `Payload::try_from`, checking the content of ping and pong packets as given
+    pub fn try_from_with_strictness(
+        value: &str,
+        strictness: Strictness,
+    ) -> Result<Payload, PacketParsingError> {
+        let config = ParserConfig {
+            strictness,
+            ..ParserConfig::default()
+        };
+        Payload::try_from_with_config(value, &config)
+    }
+
+    /// Same as `Payload::try_from`, splitting the packets on the separator of the config,
+    /// checking the content of ping and pong packets with its strictness and decoding
+    /// url-safe base64 binary packets if it is lenient about them
+    pub fn try_from_with_config(
+        value: &str,
+        config: &ParserConfig,
+    ) -> Result<Payload, PacketParsingError> {
+        if value.is_empty() {
+            return Err(PacketParsingError::EmptyString);
+        }
+        // one packet more than separators, so that the packets are never reallocated
+        let mut payload = Payload {
+            packets: Vec::with_capacity(value.matches(config.separator).count() + 1),
+        };
+        for (i, packet_str) in value.split(config.separator).enumerate() {
+            let packet =
+                Packet::parse(packet_str, usize::MAX, config).map_err(|e| e.in_payload(i))?;
+            payload.packets.push(packet);
+        }
+        Ok(payload)
+    }
+
+    /// Parses a payload framed according to the given protocol version
+    pub fn try_from_version(
+        value: &str,
+        version: ProtocolVersion,
+    ) -> Result<Payload, PacketParsingError> {
+        match version {
+            ProtocolVersion::V3 => Payload::try_from_v3(value),
+            ProtocolVersion::V4 => Payload::try_from(value),
+        }
+    }
+
+    /// Encodes the payload framed according to the given protocol version
+    pub fn encode_version(&self, version: ProtocolVersion) -> String {
+        match version {
+            ProtocolVersion::V3 => self.encode_v3(),
+            ProtocolVersion::V4 => self.encode(),
+        }
+    }
+
+    /// A V3 payload is a sequence of `<len>:<packet>`, where the length is the number of UTF-16
+    /// code units of the packet, as in javascript. Binary messages are written as 'b4' followed
+    /// by the base64 of the bytes. Since the packets are framed by their length, messages may
+    /// contain the V4 record separator.
+    fn try_from_v3(value: &str) -> Result<Payload, PacketParsingError> {
+        if value.is_empty() {
+            return Err(PacketParsingError::EmptyString);
+        }
         let mut payload = Payload {
             packets: Vec::new(),
         };
-        for packet_str in value.split(PACKET_SEPARATOR) {
-            payload.packets.push(Packet::try_from(packet_str)?);
+        let mut rest = value;
+        let mut index = 0;
+        while !rest.is_empty() {
+            let (len, tail) = rest
+                .split_once(':')
+                .ok_or(PacketParsingError::InvalidPacketLen)?;
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| PacketParsingError::InvalidPacketLen)?;
+            let end = utf16_prefix_end(tail, len).ok_or(PacketParsingError::InvalidPacketLen)?;
+            let packet_str = &tail[..end];
+            let packet = match packet_str.strip_prefix(BINARY_MESSAGE_CHAR) {
+                Some(binary) => match binary
+                    .strip_prefix(PacketType::Message.as_char())
+                    .map(decode_base64)
+                {
+                    Some(Ok(b)) => Packet {
+                        packet_type: PacketType::Message,
+                        data: Some(PacketData::Binary(b)),
+                    },
+                    _ => return Err(PacketParsingError::InvalidBinaryMessage.in_payload(index)),
+                },
+                None => Packet::parse_v3(packet_str).map_err(|e| e.in_payload(index))?,
+            };
+            payload.packets.push(packet);
+            rest = &tail[end..];
+            index += 1;
         }
         Ok(payload)
     }
+
+    fn encode_v3(&self) -> String {
+        self.packets
+            .iter()
+            .map(|p| {
+                let packet_str = match &p.data {
+                    Some(PacketData::Binary(bytes)) => format!(
+                        "{}{}{}",
+                        BINARY_MESSAGE_CHAR,
+                        PacketType::Message.as_char(),
+                        encode_base64(bytes)
+                    ),
+                    _ => p.to_string(),
+                };
+                format!("{}:{}", packet_str.encode_utf16().count(), packet_str)
+            })
+            .collect()
+    }
+}
+
+/// The byte index after the first `len` UTF-16 code units of `s`, or `None` if `s` is shorter
+/// or the index would split the surrogate pair of a character
+fn utf16_prefix_end(s: &str, len: usize) -> Option<usize> {
+    let mut units = 0;
+    for (i, ch) in s.char_indices() {
+        if units == len {
+            return Some(i);
+        }
+        units += ch.len_utf16();
+    }
+    (units == len).then_some(s.len())
+}
+
+impl From<Vec<Packet>> for Payload {
+    fn from(packets: Vec<Packet>) -> Self {
+        Payload::from_packets(packets)
+    }
+}
+
+impl<'a> IntoIterator for &'a Payload {
+    type Item = &'a Packet;
+    type IntoIter = core::slice::Iter<'a, Packet>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.packets.iter()
+    }
+}
+
+impl IntoIterator for Payload {
+    type Item = Packet;
+    type IntoIter = alloc::vec::IntoIter<Packet>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.packets.into_iter()
+    }
+}
+
+impl TryFrom<&[u8]> for Packet {
+    type Error = PacketParsingError;
+
+    /// Parses a packet from bytes. The base64 of a binary packet is decoded from the bytes
+    /// directly, while the other packets must be valid UTF-8.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value.split_first() {
+            Some((&prefix, encoded)) if prefix == BINARY_MESSAGE_CHAR as u8 => {
+                Packet::binary_from_base64(encoded, usize::MAX, false)
+            }
+            _ => {
+                let value = core::str::from_utf8(value).map_err(|_| PacketParsingError::NotUtf8)?;
+                Packet::try_from(value)
+            }
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Payload {
+    type Error = PacketParsingError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.is_empty() {
+            return Err(PacketParsingError::EmptyString);
+        }
+        // the separator is a single ASCII byte, which cannot occur inside a UTF-8 sequence
+        let packets = value
+            .split(|b| PACKET_SEPARATOR.as_bytes() == [*b])
+            .enumerate()
+            .map(|(i, packet)| Packet::try_from(packet).map_err(|e| e.in_payload(i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Payload { packets })
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Payload {
+    /// Parses a payload from a request body, validating the UTF-8 of each packet in place
+    /// rather than copying the body into a string first
+    pub fn try_from_bytes(value: &bytes::Bytes) -> Result<Payload, PacketParsingError> {
+        Payload::try_from(value.as_ref())
+    }
+}
+
+impl TryFrom<&str> for Payload {
+    type Error = PacketParsingError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Payload::try_from_with_strictness(value, Strictness::Strict)
+    }
+}
+
+impl FromStr for Payload {
+    type Err = PacketParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Payload::try_from(s)
+    }
 }
 
 #[cfg(test)]
@@ -174,7 +980,7 @@ mod tests {
     #[test]
     fn binary_message() {
         let mut base64_msg = "b".to_string();
-        base64_msg.push_str(base64::encode(vec![1, 2, 3]).as_str());
+        base64_msg.push_str(encode_base64(vec![1, 2, 3]).as_str());
         println!("base64 encoded message: {}", base64_msg);
         assert_eq!(
             Packet {
@@ -201,7 +1007,7 @@ mod tests {
     #[test]
     fn binary_message_payload() {
         let mut base64_msg = "b".to_string();
-        base64_msg.push_str(base64::encode(vec![1, 2, 3]).as_str());
+        base64_msg.push_str(encode_base64(vec![1, 2, 3]).as_str());
         println!("base64 encoded message: {}", base64_msg);
         assert_eq!(
             Payload {
@@ -240,9 +1046,9 @@ mod tests {
     fn multi_message_binary_payload() {
         let mut payload_msg = "4hello".to_string();
         payload_msg.push_str(PACKET_SEPARATOR);
-        let base64_msg = base64::encode(vec![1, 2, 3]);
+        let base64_msg = encode_base64(vec![1, 2, 3]);
         println!("base64 encoded message: {}", base64_msg);
-        payload_msg.push_str("b");
+        payload_msg.push('b');
         payload_msg.push_str(base64_msg.as_str());
         assert_eq!(
             Payload {
@@ -266,33 +1072,91 @@ mod tests {
         let mut payload_msg = "4hello".to_string();
         payload_msg.push_str(PACKET_SEPARATOR);
         payload_msg.push_str(PACKET_SEPARATOR);
-        let base64_msg = base64::encode(vec![1, 2, 3]);
+        let base64_msg = encode_base64(vec![1, 2, 3]);
         println!("base64 encoded message: {}", base64_msg);
-        payload_msg.push_str("b");
+        payload_msg.push('b');
         payload_msg.push_str(base64_msg.as_str());
         assert_eq!(
-            Err(PacketParsingError::EmptyString),
+            Err(PacketParsingError::EmptySegment(1)),
             Payload::try_from(payload_msg.as_str())
         );
     }
 
     #[test]
-    fn single_packet_in_payload() {
-        let mut payload_msg = "4hello".to_string();
+    fn empty_payload() {
+        assert_eq!(Err(PacketParsingError::EmptyString), Payload::try_from(""));
         assert_eq!(
-            Payload {
-                packets: vec![Packet {
-                    packet_type: PacketType::Message,
-                    data: Some(PacketData::String("hello".to_string())),
-                }]
-            },
+            Err(PacketParsingError::EmptySegment(1)),
+            Payload::try_from("4hello\x1e")
+        );
+    }
+
+    #[test]
+    fn invalid_packet_index_in_payload() {
+        match Payload::try_from("4hello\x1e4\x1e1\x1e2foo") {
+            Err(PacketParsingError::InvalidPayloadPacket { index, source }) => {
+                assert_eq!(3, index);
+                assert_eq!(PacketParsingError::InvalidPing, *source);
+            }
+            res => panic!("expected an invalid payload packet, got {:?}", res),
+        }
+        assert_eq!(
+            Err(PacketParsingError::InvalidPayloadPacket {
+                index: 2,
+                source: Box::new(PacketParsingError::InvalidChar)
+            }),
+            Payload::try_from("4hello\x1e4world\x1e9")
+        );
+        // a single packet error is not wrapped
+        assert_eq!(Err(PacketParsingError::InvalidChar), Packet::try_from("9"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn invalid_payload_packet_source() {
+        use std::error::Error;
+
+        let err = Payload::try_from("4hello\x1e2foo").unwrap_err();
+        assert_eq!("invalid packet at index 1 of payload", err.to_string());
+        let source = err.source().unwrap();
+        assert_eq!(
+            Some(&PacketParsingError::InvalidPing),
+            source.downcast_ref::<PacketParsingError>()
+        );
+        assert!(source.source().is_none());
+        assert!(PacketParsingError::InvalidChar.source().is_none());
+    }
+
+    #[test]
+    fn error_display() {
+        // the messages do not depend on the `std` feature
+        assert_eq!(
+            "empty packet at index 2 of payload",
+            PacketParsingError::EmptySegment(2).to_string()
+        );
+        assert_eq!(
+            "packet 1 contains the record separator",
+            PacketEncodingError::SeparatorInPacket(1).to_string()
+        );
+    }
+
+    #[test]
+    fn single_packet_in_payload() {
+        let payload_msg = "4hello".to_string();
+        assert_eq!(
+            Payload {
+                packets: vec![Packet {
+                    packet_type: PacketType::Message,
+                    data: Some(PacketData::String("hello".to_string())),
+                }]
+            },
             Payload::try_from(payload_msg.as_str()).unwrap()
         );
     }
 
     #[test]
     fn probe_ping_packet() {
-        let mut payload_msg = "2probe".to_string();
+        let payload_msg = "2probe".to_string();
         assert_eq!(
             Payload {
                 packets: vec![Packet {
@@ -304,9 +1168,340 @@ mod tests {
         );
     }
 
+    #[test]
+    fn packet_round_trip() {
+        let packets = vec![
+            Packet {
+                packet_type: PacketType::Open,
+                data: None,
+            },
+            Packet {
+                packet_type: PacketType::Close,
+                data: None,
+            },
+            Packet {
+                packet_type: PacketType::Ping,
+                data: None,
+            },
+            Packet {
+                packet_type: PacketType::Ping,
+                data: Some(PacketData::String("probe".to_string())),
+            },
+            Packet {
+                packet_type: PacketType::Pong,
+                data: None,
+            },
+            Packet {
+                packet_type: PacketType::Pong,
+                data: Some(PacketData::String("probe".to_string())),
+            },
+            Packet {
+                packet_type: PacketType::Message,
+                data: Some(PacketData::String("hello".to_string())),
+            },
+            Packet {
+                packet_type: PacketType::Message,
+                data: Some(PacketData::Binary(vec![1, 2, 3])),
+            },
+            Packet {
+                packet_type: PacketType::Upgrade,
+                data: None,
+            },
+            Packet {
+                packet_type: PacketType::Noop,
+                data: None,
+            },
+        ];
+        for packet in packets {
+            assert_eq!(
+                packet,
+                Packet::try_from(packet.to_string().as_str()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn encode_packet() {
+        let hello = Packet {
+            packet_type: PacketType::Message,
+            data: Some(PacketData::String("hello".to_string())),
+        };
+        assert_eq!("4hello", hello.to_string());
+        let binary = Packet {
+            packet_type: PacketType::Message,
+            data: Some(PacketData::Binary(vec![1, 2, 3])),
+        };
+        assert_eq!(
+            format!("b{}", encode_base64(vec![1, 2, 3])),
+            binary.to_string()
+        );
+        let probe = Packet {
+            packet_type: PacketType::Ping,
+            data: Some(PacketData::String("probe".to_string())),
+        };
+        assert_eq!("2probe", probe.to_string());
+        let noop = Packet {
+            packet_type: PacketType::Noop,
+            data: None,
+        };
+        assert_eq!("6", noop.to_string());
+    }
+
+    #[test]
+    fn multi_message_payload_round_trip() {
+        let mut payload_msg = "4hello".to_string();
+        payload_msg.push_str(PACKET_SEPARATOR);
+        payload_msg.push_str("4world");
+        payload_msg.push_str(PACKET_SEPARATOR);
+        payload_msg.push('b');
+        payload_msg.push_str(encode_base64(vec![1, 2, 3]).as_str());
+        let payload = Payload::try_from(payload_msg.as_str()).unwrap();
+        assert_eq!(payload_msg, payload.encode());
+        assert_eq!(
+            payload,
+            Payload::try_from(payload.try_encode().unwrap().as_str()).unwrap()
+        );
+    }
+
+    #[test]
+    fn encode_separator_in_packet() {
+        let payload = Payload {
+            packets: vec![
+                Packet {
+                    packet_type: PacketType::Message,
+                    data: Some(PacketData::String("hello".to_string())),
+                },
+                Packet {
+                    packet_type: PacketType::Message,
+                    data: Some(PacketData::String(format!("a{}b", PACKET_SEPARATOR))),
+                },
+            ],
+        };
+        assert_eq!(
+            Err(PacketEncodingError::SeparatorInPacket(1)),
+            payload.try_encode()
+        );
+    }
+
+    #[test]
+    fn packet_accessors() {
+        let packet = Packet::try_from("4hello").unwrap();
+        assert_eq!(&PacketType::Message, packet.packet_type());
+        assert_eq!(
+            Some(&PacketData::String("hello".to_string())),
+            packet.data()
+        );
+        let payload = Payload::try_from("4hello").unwrap();
+        assert_eq!(&[packet], payload.packets());
+    }
+
+    #[test]
+    fn open_packet_with_handshake() {
+        let handshake = r#"{"sid":"abc","upgrades":[],"pingInterval":25000}"#;
+        let packet = Packet::try_from(format!("0{}", handshake).as_str()).unwrap();
+        assert_eq!(
+            Packet::new(
+                PacketType::Open,
+                Some(PacketData::String(handshake.to_string()))
+            ),
+            packet
+        );
+        assert_eq!(format!("0{}", handshake), packet.to_string());
+    }
+
+    #[test]
+    fn v3_multi_message_payload() {
+        let payload = Payload::try_from_version("6:4hello7:4world2", ProtocolVersion::V3).unwrap();
+        assert_eq!(
+            Payload {
+                packets: vec![
+                    Packet {
+                        packet_type: PacketType::Message,
+                        data: Some(PacketData::String("hello".to_string())),
+                    },
+                    Packet {
+                        packet_type: PacketType::Message,
+                        data: Some(PacketData::String("world2".to_string())),
+                    }
+                ]
+            },
+            payload
+        );
+        assert_eq!(
+            "6:4hello7:4world2",
+            payload.encode_version(ProtocolVersion::V3)
+        );
+    }
+
+    #[test]
+    fn v3_binary_message_payload() {
+        let payload_msg = format!("2:4é6:b4{}", encode_base64(vec![1, 2, 3]));
+        let payload = Payload::try_from_version(&payload_msg, ProtocolVersion::V3).unwrap();
+        assert_eq!(
+            Payload {
+                packets: vec![
+                    Packet {
+                        packet_type: PacketType::Message,
+                        data: Some(PacketData::String("é".to_string())),
+                    },
+                    Packet {
+                        packet_type: PacketType::Message,
+                        data: Some(PacketData::Binary(vec![1, 2, 3])),
+                    }
+                ]
+            },
+            payload
+        );
+        assert_eq!(payload_msg, payload.encode_version(ProtocolVersion::V3));
+    }
+
+    #[test]
+    fn v3_invalid_packet_len() {
+        assert_eq!(
+            Err(PacketParsingError::InvalidPacketLen),
+            Payload::try_from_version("7:4hello", ProtocolVersion::V3)
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidPacketLen),
+            Payload::try_from_version("4hello", ProtocolVersion::V3)
+        );
+    }
+
+    #[test]
+    fn v3_utf16_packet_len() {
+        // the emoji is a single char, but two UTF-16 code units
+        let payload =
+            Payload::from_packets(vec![Packet::message_text("😀"), Packet::message_text("é")]);
+        assert_eq!("3:4😀2:4é", payload.encode_version(ProtocolVersion::V3));
+        assert_eq!(
+            Ok(payload),
+            Payload::try_from_version("3:4😀2:4é", ProtocolVersion::V3)
+        );
+        // a length ending within the surrogate pair of the emoji is invalid
+        assert_eq!(
+            Err(PacketParsingError::InvalidPacketLen),
+            Payload::try_from_version("2:4😀", ProtocolVersion::V3)
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidPacketLen),
+            Payload::try_from_version("4:4😀", ProtocolVersion::V3)
+        );
+    }
+
+    #[test]
+    fn v3_message_with_separator() {
+        let payload =
+            Payload::from_packets(vec![Packet::message_text("hello\x1eworld"), Packet::ping()]);
+        assert_eq!(
+            "12:4hello\x1eworld1:2",
+            payload.encode_version(ProtocolVersion::V3)
+        );
+        assert_eq!(
+            Ok(payload),
+            Payload::try_from_version("12:4hello\x1eworld1:2", ProtocolVersion::V3)
+        );
+    }
+
+    #[test]
+    fn v4_is_default_version() {
+        assert_eq!(ProtocolVersion::V4, ProtocolVersion::default());
+        assert_eq!(
+            Payload::try_from("4hello"),
+            Payload::try_from_version("4hello", ProtocolVersion::default())
+        );
+    }
+
+    #[test]
+    fn separator_in_message() {
+        let msg = format!("4hello{}world", PACKET_SEPARATOR);
+        assert_eq!(
+            Err(PacketParsingError::SeparatorInMessage),
+            Packet::try_from(msg.as_str())
+        );
+        assert_eq!(
+            Err(PacketParsingError::SeparatorInMessage),
+            Packet::try_from(format!("4{}", PACKET_SEPARATOR).as_str())
+        );
+    }
+
+    #[test]
+    fn binary_message_padding() {
+        assert_eq!(
+            Packet {
+                packet_type: PacketType::Message,
+                data: Some(PacketData::Binary(vec![1, 2])),
+            },
+            Packet::try_from("bAQI=").unwrap()
+        );
+        // the standard engine requires canonical padding
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from("bAQI")
+        );
+    }
+
+    #[test]
+    fn binary_message_whitespace() {
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from("bAQ ID")
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from("bAQID\n")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_mixed_payload() {
+        let mut payload_msg = "4hello".to_string();
+        payload_msg.push_str(PACKET_SEPARATOR);
+        payload_msg.push_str("bAQID");
+        payload_msg.push_str(PACKET_SEPARATOR);
+        payload_msg.push_str("2probe");
+        let payload = Payload::try_from(payload_msg.as_str()).unwrap();
+        let json = serde_json::to_string(&payload).unwrap();
+        assert!(json.contains(r#"{"Binary":"AQID"}"#));
+        assert_eq!(payload, serde_json::from_str::<Payload>(&json).unwrap());
+    }
+
+    #[test]
+    fn payload_limit() {
+        let payload_msg = "4hello\x1e4world";
+        assert_eq!(
+            Payload::try_from(payload_msg),
+            Payload::try_from_with_limit(payload_msg, payload_msg.len())
+        );
+        assert_eq!(
+            Err(PacketParsingError::PayloadTooLarge(payload_msg.len())),
+            Payload::try_from_with_limit(payload_msg, payload_msg.len() - 1)
+        );
+    }
+
+    #[test]
+    fn packet_constructors() {
+        let cases = vec![
+            (Packet::open(r#"{"sid":"abc"}"#), r#"0{"sid":"abc"}"#),
+            (Packet::close(), "1"),
+            (Packet::ping(), "2"),
+            (Packet::pong(), "3"),
+            (Packet::ping_probe(), "2probe"),
+            (Packet::pong_probe(), "3probe"),
+            (Packet::message_text("hello"), "4hello"),
+            (Packet::message_binary(vec![1, 2, 3]), "bAQID"),
+            (Packet::upgrade(), "5"),
+            (Packet::noop(), "6"),
+        ];
+        for (packet, encoded) in cases {
+            assert_eq!(encoded, packet.to_string());
+            assert_eq!(packet, Packet::try_from(encoded).unwrap());
+        }
+    }
+
     #[test]
     fn probe_pong_packet() {
-        let mut payload_msg = "3probe".to_string();
+        let payload_msg = "3probe".to_string();
         assert_eq!(
             Payload {
                 packets: vec![Packet {
@@ -317,4 +1512,440 @@ mod tests {
             Payload::try_from(payload_msg.as_str()).unwrap()
         );
     }
+
+    #[test]
+    fn iterate_payload() {
+        let payload = Payload::try_from("4hello\x1e2\x1ebAQID").unwrap();
+        let mut types = Vec::new();
+        for packet in &payload {
+            types.push(packet.packet_type().clone());
+        }
+        assert_eq!(
+            vec![PacketType::Message, PacketType::Ping, PacketType::Message],
+            types
+        );
+        assert_eq!(3, payload.iter().count());
+        let owned: Vec<Packet> = payload.into_iter().collect();
+        assert_eq!(Packet::ping(), owned[1]);
+    }
+
+    #[test]
+    fn packet_type_chars() {
+        let cases = [
+            (PacketType::Open, '0'),
+            (PacketType::Close, '1'),
+            (PacketType::Ping, '2'),
+            (PacketType::Pong, '3'),
+            (PacketType::Message, '4'),
+            (PacketType::Upgrade, '5'),
+            (PacketType::Noop, '6'),
+        ];
+        for (packet_type, ch) in cases {
+            assert_eq!(ch, packet_type.as_char());
+            assert_eq!(Some(packet_type), PacketType::from_char(ch));
+        }
+        // binary messages are identified by their prefix, not by a packet type
+        assert_eq!(None, PacketType::from_char('b'));
+        assert_eq!(None, PacketType::from_char('7'));
+    }
+
+    #[test]
+    fn oversize_binary_packet() {
+        let blob = format!("b{}", "A".repeat(10 * 1024 * 1024));
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from_with_limit(&blob, 1024)
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from_with_limit("b!!!", 1024)
+        );
+        assert_eq!(
+            Ok(Packet::message_binary(vec![1, 2, 3])),
+            Packet::try_from_with_limit("bAQID", 4)
+        );
+    }
+
+    #[test]
+    fn close_packet_with_data() {
+        assert_eq!(Ok(Packet::close()), Packet::try_from("1"));
+        assert_eq!(
+            Err(PacketParsingError::InvalidClose),
+            Packet::try_from("1extra")
+        );
+    }
+
+    #[test]
+    fn dataless_packets_with_data() {
+        assert_eq!(Ok(Packet::upgrade()), Packet::try_from("5"));
+        assert_eq!(
+            Err(PacketParsingError::InvalidUpgrade),
+            Packet::try_from("5x")
+        );
+        assert_eq!(Ok(Packet::noop()), Packet::try_from("6"));
+        assert_eq!(Err(PacketParsingError::InvalidNoop), Packet::try_from("6x"));
+        assert_eq!(
+            Err(PacketParsingError::InvalidPayloadPacket {
+                index: 1,
+                source: Box::new(PacketParsingError::InvalidUpgrade)
+            }),
+            Payload::try_from("4hello\x1e5foo")
+        );
+    }
+
+    #[test]
+    fn probe_and_heartbeat_packets() {
+        let ping = Packet::try_from("2").unwrap();
+        assert!(ping.is_heartbeat_ping() && !ping.is_heartbeat_pong() && !ping.is_probe());
+        let pong = Packet::try_from("3").unwrap();
+        assert!(pong.is_heartbeat_pong() && !pong.is_heartbeat_ping() && !pong.is_probe());
+        let ping_probe = Packet::try_from("2probe").unwrap();
+        assert!(ping_probe.is_probe() && !ping_probe.is_heartbeat_ping());
+        let pong_probe = Packet::try_from("3probe").unwrap();
+        assert!(pong_probe.is_probe() && !pong_probe.is_heartbeat_pong());
+        assert!(!Packet::message_text("probe").is_probe());
+    }
+
+    #[test]
+    fn packet_byte_len() {
+        let packets = [
+            Packet::open("{\"sid\":\"abc\"}"),
+            Packet::close(),
+            Packet::ping(),
+            Packet::pong_probe(),
+            Packet::message_text("héllo"),
+            Packet::upgrade(),
+            Packet::noop(),
+        ];
+        for packet in packets {
+            let len = packet.to_string().len();
+            assert_eq!(len, packet.byte_len(TransportKind::Polling));
+            assert_eq!(len, packet.byte_len(TransportKind::Websocket));
+        }
+        for n in 0..8 {
+            let packet = Packet::message_binary(vec![0xff; n]);
+            assert_eq!(
+                packet.to_string().len(),
+                packet.byte_len(TransportKind::Polling)
+            );
+            assert_eq!(n, packet.byte_len(TransportKind::Websocket));
+        }
+    }
+
+    #[test]
+    fn packet_from_bytes() {
+        assert_eq!(
+            Ok(Packet::message_text("héllo")),
+            Packet::try_from("4héllo".as_bytes())
+        );
+        assert_eq!(
+            Ok(Packet::message_binary(vec![1, 2, 3])),
+            Packet::try_from(&b"bAQID"[..])
+        );
+        assert_eq!(
+            Err(PacketParsingError::NotUtf8),
+            Packet::try_from(&b"4\xff\xfe"[..])
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage),
+            Packet::try_from(&b"b\xff"[..])
+        );
+    }
+
+    #[test]
+    fn payload_from_bytes() {
+        let payload_msg = "4héllo\x1ebAQID\x1e2";
+        assert_eq!(
+            Payload::try_from(payload_msg),
+            Payload::try_from(payload_msg.as_bytes())
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidPayloadPacket {
+                index: 1,
+                source: Box::new(PacketParsingError::NotUtf8)
+            }),
+            Payload::try_from(&b"4hello\x1e4\xc3"[..])
+        );
+        assert_eq!(
+            Err(PacketParsingError::EmptyString),
+            Payload::try_from(&b""[..])
+        );
+    }
+
+    #[test]
+    fn transport_kind_names() {
+        for kind in [TransportKind::Polling, TransportKind::Websocket] {
+            assert_eq!(Some(kind), TransportKind::from_name(kind.as_str()));
+        }
+        assert_eq!(
+            Some(TransportKind::Websocket),
+            TransportKind::from_name("websocket")
+        );
+        assert_eq!(None, TransportKind::from_name("Polling"));
+        assert_eq!(None, TransportKind::from_name("flashsocket"));
+        assert_eq!(None, TransportKind::from_name(""));
+    }
+
+    #[test]
+    fn packet_content_hash() {
+        let packets = [
+            Packet::message_text("hello"),
+            Packet::message_binary(b"hello".to_vec()),
+            Packet::message_text("hellp"),
+            Packet::message_text(""),
+            Packet::ping(),
+            Packet::pong(),
+            Packet::ping_probe(),
+        ];
+        for (i, a) in packets.iter().enumerate() {
+            assert_eq!(a.content_hash(), a.clone().content_hash());
+            for b in &packets[i + 1..] {
+                assert_ne!(a.content_hash(), b.content_hash(), "{} and {}", a, b);
+            }
+        }
+        let large = Packet::message_binary(vec![7; 1 << 16]);
+        assert_eq!(
+            large.content_hash(),
+            Packet::message_binary(vec![7; 1 << 16]).content_hash()
+        );
+    }
+
+    #[test]
+    fn packet_data_accessors() {
+        let text = PacketData::String("héllo".to_string());
+        assert_eq!(Some("héllo"), text.as_str());
+        assert_eq!("héllo".as_bytes(), text.as_bytes());
+        assert_eq!(6, text.len());
+        assert!(!text.is_empty());
+
+        let binary = PacketData::Binary(vec![1, 2, 3]);
+        assert_eq!(None, binary.as_str());
+        assert_eq!(&[1, 2, 3], binary.as_bytes());
+        assert_eq!(3, binary.len());
+        assert!(PacketData::Binary(vec![]).is_empty());
+    }
+
+    #[test]
+    fn peek_packet_types() {
+        assert_eq!(Some(PacketType::Ping), peek_packet_type("2"));
+        assert_eq!(Some(PacketType::Pong), peek_packet_type("3probe"));
+        assert_eq!(Some(PacketType::Message), peek_packet_type("4hello"));
+        assert_eq!(Some(PacketType::Message), peek_packet_type("bAQID"));
+        assert_eq!(None, peek_packet_type(""));
+        assert_eq!(None, peek_packet_type("9"));
+        assert_eq!(None, peek_packet_type("é"));
+    }
+
+    #[test]
+    fn parse_from_str() {
+        assert_eq!(
+            Ok(Packet::message_text("hello")),
+            "4hello".parse::<Packet>()
+        );
+        assert_eq!(Ok(Packet::ping()), "2".parse());
+        assert_eq!(Err(PacketParsingError::EmptyString), "".parse::<Packet>());
+        assert_eq!(Packet::try_from("9"), "9".parse::<Packet>());
+
+        let payload: Payload = "4hello\x1e2\x1ebAQID".parse().unwrap();
+        assert_eq!(
+            &[
+                Packet::message_text("hello"),
+                Packet::ping(),
+                Packet::message_binary(vec![1, 2, 3])
+            ],
+            payload.packets()
+        );
+        assert_eq!(Payload::try_from("4a\x1e"), "4a\x1e".parse::<Payload>());
+    }
+
+    #[test]
+    fn packet_data_try_as_str() {
+        assert_eq!(
+            Ok("héllo"),
+            PacketData::String("héllo".to_string()).try_as_str()
+        );
+        // binary data decoded from base64 is text only if its bytes happen to be UTF-8
+        let packet = Packet::try_from("baMOpbGxv").unwrap();
+        assert_eq!(Ok("héllo"), packet.data().unwrap().try_as_str());
+        let packet = Packet::try_from("b/w==").unwrap();
+        assert_eq!(Some(&PacketData::Binary(vec![0xff])), packet.data());
+        assert!(packet.data().unwrap().try_as_str().is_err());
+        assert!(PacketData::Binary(vec![b'h', 0xc3]).try_as_str().is_err());
+    }
+
+    #[test]
+    fn empty_message_round_trip() {
+        let packet = Packet::try_from("4").unwrap();
+        assert_eq!(Packet::message_text(""), packet);
+        assert_eq!(Some(&PacketData::String(String::new())), packet.data());
+        assert!(packet.is_empty_message());
+        assert_eq!("4", packet.to_string());
+
+        let payload = Payload::try_from("4\x1e4hello\x1e4").unwrap();
+        let empty: Vec<_> = payload.iter().map(Packet::is_empty_message).collect();
+        assert_eq!(vec![true, false, true], empty);
+        assert_eq!("4\x1e4hello\x1e4", payload.encode());
+
+        assert!(!Packet::message_text("hello").is_empty_message());
+        assert!(!Packet::message_binary(vec![]).is_empty_message());
+        assert!(!Packet::noop().is_empty_message());
+    }
+
+    #[test]
+    fn build_payload() {
+        let mut payload = Payload::single(Packet::message_text("hello"));
+        assert_eq!(1, payload.len());
+        assert_eq!("4hello", payload.encode());
+        payload.push(Packet::message_binary(vec![1, 2, 3]));
+        payload.push(Packet::noop());
+        assert_eq!(3, payload.len());
+        assert_eq!("4hello\x1ebAQID\x1e6", payload.encode());
+
+        let payload = Payload::from_packets(vec![Packet::ping(), Packet::close()]);
+        assert_eq!(2, payload.len());
+        assert_eq!("2\x1e1", payload.encode());
+        assert!(Payload::from_packets(vec![]).is_empty());
+    }
+
+    #[test]
+    fn validate_open_packets() {
+        let payload = Payload::try_from("0{}\x1e4hello").unwrap();
+        assert_eq!(Ok(()), payload.validate());
+        let payload = Payload::try_from("0{}\x1e0{}").unwrap();
+        assert_eq!(
+            Err(PacketParsingError::UnexpectedOpen(1)),
+            payload.validate()
+        );
+        let payload = Payload::try_from("4hello\x1e0{}").unwrap();
+        assert_eq!(
+            Err(PacketParsingError::UnexpectedOpen(1)),
+            payload.validate()
+        );
+        assert_eq!(Ok(()), Payload::from_packets(vec![]).validate());
+    }
+
+    #[test]
+    fn ping_strictness() {
+        assert_eq!(
+            Err(PacketParsingError::InvalidPing),
+            Packet::try_from_with_strictness("2 ", Strictness::Strict)
+        );
+        assert_eq!(Err(PacketParsingError::InvalidPing), Packet::try_from("2 "));
+        assert_eq!(
+            Ok(Packet::ping()),
+            Packet::try_from_with_strictness("2 ", Strictness::Lenient)
+        );
+        assert_eq!(
+            Ok(Packet::pong_probe()),
+            Packet::try_from_with_strictness("3 probe\n", Strictness::Lenient)
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidPong),
+            Packet::try_from_with_strictness("3 pro be", Strictness::Lenient)
+        );
+        assert_eq!(
+            Ok(Payload::from_packets(vec![
+                Packet::pong(),
+                Packet::message_text(" hi ")
+            ])),
+            Payload::try_from_with_strictness("3 \x1e4 hi ", Strictness::Lenient)
+        );
+    }
+
+    #[test]
+    fn custom_separator() {
+        let config = ParserConfig {
+            separator: '|',
+            ..ParserConfig::default()
+        };
+        let payload = Payload::from_packets(vec![
+            Packet::message_text("hello\x1eworld"),
+            Packet::ping(),
+            Packet::message_binary(vec![1, 2, 3]),
+        ]);
+        assert_eq!(
+            Ok(payload.clone()),
+            Payload::try_from_with_config("4hello\x1eworld|2|bAQID", &config)
+        );
+        assert_eq!(
+            "4hello\x1eworld|2|bAQID",
+            payload.encode_with_config(&config)
+        );
+        // only the configured separator is forbidden in messages
+        assert_eq!(
+            Err(PacketParsingError::SeparatorInMessage),
+            Packet::try_from("4hello\x1eworld")
+        );
+        assert_eq!(
+            Payload::try_from("4hello\x1e2"),
+            Payload::try_from_with_config("4hello\x1e2", &ParserConfig::default())
+        );
+    }
+
+    #[test]
+    fn url_safe_base64_binary() {
+        // [251, 255, 191] is "+/+/" in the standard alphabet and "-_-_" in the url-safe one
+        let bytes = vec![251, 255, 191];
+        assert_eq!("+/+/", encode_base64(&bytes));
+        let lenient = ParserConfig {
+            lenient_base64: true,
+            ..ParserConfig::default()
+        };
+        let expected = Payload::from_packets(vec![
+            Packet::message_binary(bytes.clone()),
+            Packet::message_text("hi"),
+        ]);
+
+        // spec-strict by default
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage.in_payload(0)),
+            Payload::try_from("b-_-_\x1e4hi")
+        );
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage.in_payload(0)),
+            Payload::try_from_with_config("b-_-_\x1e4hi", &ParserConfig::default())
+        );
+
+        assert_eq!(
+            Ok(expected.clone()),
+            Payload::try_from_with_config("b-_-_\x1e4hi", &lenient)
+        );
+        // the standard alphabet is still accepted, and url-safe padding is optional
+        assert_eq!(
+            Ok(expected),
+            Payload::try_from_with_config("b+/+/\x1e4hi", &lenient)
+        );
+        assert_eq!(
+            Ok(Payload::single(Packet::message_binary(vec![251, 255]))),
+            Payload::try_from_with_config("b-_8", &lenient)
+        );
+        assert_eq!(
+            Ok(Payload::single(Packet::message_binary(vec![251, 255]))),
+            Payload::try_from_with_config("b-_8=", &lenient)
+        );
+        // anything else is still invalid
+        assert_eq!(
+            Err(PacketParsingError::InvalidBinaryMessage.in_payload(0)),
+            Payload::try_from_with_config("b-_*_", &lenient)
+        );
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn payload_from_bytes_buffer() {
+        let payload_msg = "4héllo\x1ebAQID\x1e2";
+        let bytes = bytes::Bytes::from_static(payload_msg.as_bytes());
+        assert_eq!(
+            Payload::try_from(payload_msg),
+            Payload::try_from_bytes(&bytes)
+        );
+        let bytes = bytes::Bytes::from_static(b"4\xff");
+        assert_eq!(
+            Err(PacketParsingError::InvalidPayloadPacket {
+                index: 0,
+                source: Box::new(PacketParsingError::NotUtf8)
+            }),
+            Payload::try_from_bytes(&bytes)
+        );
+    }
 }
@@ -0,0 +1,102 @@
+//! A typed view of a packet, so that callers match once on the packet rather than on both
+//! its type and its data.
+
+use crate::{Packet, PacketData, PacketParsingError, PacketType};
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// The handshake json carried by the Open packet, with the durations in milliseconds
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Handshake {
+    pub sid: String,
+    pub upgrades: Vec<String>,
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+    pub max_payload: u64,
+}
+
+/// A packet with its type and data flattened into a single variant
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ParsedPacket {
+    Open(Handshake),
+    Close,
+    Ping { probe: bool },
+    Pong { probe: bool },
+    Message(PacketData),
+    Upgrade,
+    Noop,
+}
+
+impl Packet {
+    /// Converts the packet into a `ParsedPacket`, failing with `InvalidHandshake`
+    /// if it is an Open packet without a valid handshake json
+    pub fn into_parsed(self) -> Result<ParsedPacket, PacketParsingError> {
+        let probe = self.is_probe();
+        Ok(match (self.packet_type, self.data) {
+            (PacketType::Open, Some(PacketData::String(handshake))) => ParsedPacket::Open(
+                serde_json::from_str(&handshake)
+                    .map_err(|_| PacketParsingError::InvalidHandshake)?,
+            ),
+            (PacketType::Open, _) => return Err(PacketParsingError::InvalidHandshake),
+            (PacketType::Close, _) => ParsedPacket::Close,
+            (PacketType::Ping, _) => ParsedPacket::Ping { probe },
+            (PacketType::Pong, _) => ParsedPacket::Pong { probe },
+            (PacketType::Message, data) => {
+                ParsedPacket::Message(data.unwrap_or_else(|| PacketData::String(String::new())))
+            }
+            (PacketType::Upgrade, _) => ParsedPacket::Upgrade,
+            (PacketType::Noop, _) => ParsedPacket::Noop,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(value: &str) -> Result<ParsedPacket, PacketParsingError> {
+        Packet::try_from(value)?.into_parsed()
+    }
+
+    #[test]
+    fn wire_strings_into_parsed() {
+        let open = r#"0{"sid":"abc","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":20000,"maxPayload":1000000}"#;
+        assert_eq!(
+            Ok(ParsedPacket::Open(Handshake {
+                sid: "abc".into(),
+                upgrades: vec!["websocket".into()],
+                ping_interval: 25000,
+                ping_timeout: 20000,
+                max_payload: 1_000_000,
+            })),
+            parsed(open)
+        );
+        assert_eq!(Ok(ParsedPacket::Close), parsed("1"));
+        assert_eq!(Ok(ParsedPacket::Ping { probe: false }), parsed("2"));
+        assert_eq!(Ok(ParsedPacket::Ping { probe: true }), parsed("2probe"));
+        assert_eq!(Ok(ParsedPacket::Pong { probe: false }), parsed("3"));
+        assert_eq!(Ok(ParsedPacket::Pong { probe: true }), parsed("3probe"));
+        assert_eq!(
+            Ok(ParsedPacket::Message(PacketData::String("hello".into()))),
+            parsed("4hello")
+        );
+        assert_eq!(
+            Ok(ParsedPacket::Message(PacketData::Binary(vec![1, 2, 3]))),
+            parsed("bAQID")
+        );
+        assert_eq!(Ok(ParsedPacket::Upgrade), parsed("5"));
+        assert_eq!(Ok(ParsedPacket::Noop), parsed("6"));
+    }
+
+    #[test]
+    fn invalid_handshake() {
+        assert_eq!(Err(PacketParsingError::InvalidHandshake), parsed("0"));
+        assert_eq!(Err(PacketParsingError::InvalidHandshake), parsed("0{}"));
+        assert_eq!(
+            Err(PacketParsingError::InvalidHandshake),
+            parsed(r#"0{"sid":"abc""#)
+        );
+    }
+}
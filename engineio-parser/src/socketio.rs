@@ -0,0 +1,133 @@
+//! The framing boundary between engine.io and socket.io. A socket.io packet travels as the text
+//! of an engine.io Message packet, starting with the digit of its socket.io packet type,
+//! e.g. `42["event",{"a":1}]` is the engine.io Message of the socket.io Event `2["event",{"a":1}]`.
+//! The rest of the socket.io packet, i.e. the namespace, ack id and json, is left to the caller.
+
+use crate::{Packet, PacketData, PacketType};
+use alloc::format;
+use core::fmt;
+
+/// The socket.io packet types, carried as the first digit of the text
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SocketIoPacketType {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    ConnectError,
+    BinaryEvent,
+    BinaryAck,
+}
+
+impl SocketIoPacketType {
+    pub fn from_char(ch: char) -> Option<SocketIoPacketType> {
+        match ch {
+            '0' => Some(SocketIoPacketType::Connect),
+            '1' => Some(SocketIoPacketType::Disconnect),
+            '2' => Some(SocketIoPacketType::Event),
+            '3' => Some(SocketIoPacketType::Ack),
+            '4' => Some(SocketIoPacketType::ConnectError),
+            '5' => Some(SocketIoPacketType::BinaryEvent),
+            '6' => Some(SocketIoPacketType::BinaryAck),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> char {
+        match self {
+            SocketIoPacketType::Connect => '0',
+            SocketIoPacketType::Disconnect => '1',
+            SocketIoPacketType::Event => '2',
+            SocketIoPacketType::Ack => '3',
+            SocketIoPacketType::ConnectError => '4',
+            SocketIoPacketType::BinaryEvent => '5',
+            SocketIoPacketType::BinaryAck => '6',
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SocketIoError {
+    /// Only the text of Message packets carries socket.io packets
+    NotATextMessage,
+    /// The text does not start with the digit of a socket.io packet type
+    InvalidPacketType,
+}
+
+impl fmt::Display for SocketIoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketIoError::NotATextMessage => write!(f, "not a text message packet"),
+            SocketIoError::InvalidPacketType => write!(f, "invalid socket.io packet type"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SocketIoError {}
+
+/// Wraps a socket.io packet into an engine.io Message, `body` being everything after the type,
+/// e.g. `["event",{"a":1}]`
+pub fn wrap(packet_type: SocketIoPacketType, body: &str) -> Packet {
+    Packet::message_text(format!("{}{}", packet_type.as_char(), body))
+}
+
+/// Unwraps the socket.io packet of an engine.io Message, returning its type and the rest of it
+pub fn unwrap(packet: &Packet) -> Result<(SocketIoPacketType, &str), SocketIoError> {
+    let text = match (packet.packet_type(), packet.data()) {
+        (PacketType::Message, Some(PacketData::String(text))) => text,
+        _ => return Err(SocketIoError::NotATextMessage),
+    };
+    let mut chars = text.chars();
+    let packet_type = chars
+        .next()
+        .and_then(SocketIoPacketType::from_char)
+        .ok_or(SocketIoError::InvalidPacketType)?;
+    Ok((packet_type, chars.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_and_unwrap_event() {
+        let body = r#"["event",{"a":1}]"#;
+        let packet = wrap(SocketIoPacketType::Event, body);
+        assert_eq!(r#"42["event",{"a":1}]"#, packet.to_string());
+        assert_eq!(Ok((SocketIoPacketType::Event, body)), unwrap(&packet));
+
+        let parsed = Packet::try_from(r#"43/chat,12["ok"]"#).unwrap();
+        assert_eq!(
+            Ok((SocketIoPacketType::Ack, r#"/chat,12["ok"]"#)),
+            unwrap(&parsed)
+        );
+        for ch in '0'..='6' {
+            let packet_type = SocketIoPacketType::from_char(ch).unwrap();
+            assert_eq!(ch, packet_type.as_char());
+            assert_eq!(Ok((packet_type, "")), unwrap(&wrap(packet_type, "")));
+        }
+    }
+
+    #[test]
+    fn unwrap_errors() {
+        assert_eq!(
+            Err(SocketIoError::NotATextMessage),
+            unwrap(&Packet::message_binary(vec![2]))
+        );
+        assert_eq!(Err(SocketIoError::NotATextMessage), unwrap(&Packet::ping()));
+        assert_eq!(
+            Err(SocketIoError::InvalidPacketType),
+            unwrap(&Packet::message_text("7"))
+        );
+        assert_eq!(
+            Err(SocketIoError::InvalidPacketType),
+            unwrap(&Packet::message_text(""))
+        );
+        // the messages do not depend on the `std` feature
+        assert_eq!(
+            "invalid socket.io packet type",
+            SocketIoError::InvalidPacketType.to_string()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Entry points for a browser client compiled to `wasm32-unknown-unknown`.
+//! Payloads are passed to javascript as arrays of packets, e.g.
+//! `[{ packet_type: "Message", data: { String: "hello" } }]`,
+//! and errors are thrown as their message.
+
+use crate::{Packet, Payload};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use wasm_bindgen::prelude::*;
+
+/// Parses a V4 payload into an array of packets
+#[wasm_bindgen(js_name = parsePayload)]
+pub fn parse_payload(payload: &str) -> Result<JsValue, JsValue> {
+    let payload = Payload::try_from(payload).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    serde_wasm_bindgen::to_value(payload.packets()).map_err(JsValue::from)
+}
+
+/// Encodes an array of packets into a V4 payload
+#[wasm_bindgen(js_name = encodePayload)]
+pub fn encode_payload(packets: JsValue) -> Result<String, JsValue> {
+    let packets: Vec<Packet> = serde_wasm_bindgen::from_value(packets)?;
+    Payload::from(packets)
+        .try_encode()
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    #[wasm_bindgen_test]
+    fn payload_round_trip() {
+        let packets = parse_payload("4hello\x1ebAQID").unwrap();
+        assert_eq!("4hello\x1ebAQID", encode_payload(packets).unwrap());
+        assert!(parse_payload("9").is_err());
+    }
+}
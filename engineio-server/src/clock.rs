@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+/// The trait `Clock` is the source of time of the heartbeat, i.e. when pings are sent
+/// and when a missing pong times out. It is `TokioClock` unless set with `EngineBuilder::clock`.
+#[async_trait]
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once the clock reaches the deadline
+    async fn sleep_until(&self, deadline: Instant);
+}
+
+/// A shared clock, e.g. a `MockClock` that a test keeps to advance it
+#[async_trait]
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        self.as_ref().now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        self.as_ref().sleep_until(deadline).await
+    }
+}
+
+/// The clock of the tokio runtime, which also follows `tokio::time::pause` and `advance`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        time::sleep_until(deadline).await
+    }
+}
+
+/// A clock that only moves when advanced, so that tests of the heartbeat are deterministic
+/// and never wait for real time to pass
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+    /// Notified when the clock is advanced
+    advanced: Notify,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+            advanced: Notify::new(),
+        }
+    }
+
+    /// Moves the clock forward, waking up the sleepers whose deadline is reached
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.advanced.notify_waiters();
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        loop {
+            let advanced = self.advanced.notified();
+            if self.now() >= deadline {
+                return;
+            }
+            advanced.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn mock_clock_sleep() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut sleep = Box::pin(clock.sleep_until(start + Duration::from_secs(10)));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(9));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!((&mut sleep).now_or_never().is_some());
+        assert_eq!(start + Duration::from_secs(10), clock.now());
+        // a deadline in the past completes right away
+        assert!(clock.sleep_until(start).now_or_never().is_some());
+    }
+}
@@ -0,0 +1,349 @@
+//! The `permessage-deflate` websocket extension (RFC 7692): negotiating the extension from the
+//! client's offer, and compressing or inflating messages with the raw deflate format it uses.
+//! Compressed messages are exchanged as `Frame::Compressed`, i.e. with the RSV1 bit set, which
+//! the websocket of axum does not expose. The engine thus only compresses the messages of
+//! sockets reporting the extension with `TransportIo::permessage_deflate`, which `AxumIo` never
+//! does: compression is only available to custom `TransportIo` adapters.
+
+use crate::engine::EngineError;
+use crate::io::{Compressed, Frame, TransportIo};
+use crate::transport::TransportParsingError;
+use async_trait::async_trait;
+use axum::http::{header, HeaderMap};
+use eio_parser::PacketParsingError;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io::{self, ErrorKind};
+
+const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+/// Each message is inflated on its own, so the client must not refer to its previous messages
+const RESPONSE_HEADER: &str = "permessage-deflate; client_no_context_takeover";
+/// Each compressed message ends with an empty deflate block, which is removed before sending
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// How much the buffer of an inflated message grows at once
+const INFLATE_CHUNK: usize = 16 * 1024;
+
+/// Whether the client offered `permessage-deflate` in its `Sec-WebSocket-Extensions` header
+pub fn offers_permessage_deflate(extensions: &str) -> bool {
+    extensions
+        .split(',')
+        .filter_map(|ext| ext.split(';').next())
+        .any(|name| name.trim().eq_ignore_ascii_case(PERMESSAGE_DEFLATE))
+}
+
+/// Same as `offers_permessage_deflate`, for any of the `Sec-WebSocket-Extensions` headers
+/// of the upgrade request
+pub fn headers_offer_permessage_deflate(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(offers_permessage_deflate)
+}
+
+/// The `Deflate` struct compresses outbound messages of at least `threshold` bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deflate {
+    pub threshold: usize,
+}
+
+impl Deflate {
+    pub fn new(threshold: usize) -> Deflate {
+        Deflate { threshold }
+    }
+
+    /// The value of the `Sec-WebSocket-Extensions` response header accepting the extension.
+    /// It asks the client for `client_no_context_takeover`, as `inflate` starts each message
+    /// with a fresh context, while the messages sent by `compress` never refer to each other.
+    pub fn response_header(&self) -> &'static str {
+        RESPONSE_HEADER
+    }
+
+    /// Compresses the message, or returns `None` if it is smaller than the threshold
+    /// and should be sent uncompressed. The message ends with a sync flush rather than a final
+    /// block, whose empty tail is removed as mandated by the extension.
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.threshold {
+            return None;
+        }
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(data.len() / 2 + DEFLATE_TAIL.len());
+        loop {
+            if compressed.len() == compressed.capacity() {
+                compressed.reserve(data.len().max(64));
+            }
+            let consumed = compress.total_in() as usize;
+            compress
+                .compress_vec(&data[consumed..], &mut compressed, FlushCompress::Sync)
+                .ok()?;
+            // the flush is complete once all the input is consumed without filling the output
+            if compress.total_in() as usize == data.len()
+                && compressed.len() < compressed.capacity()
+            {
+                break;
+            }
+        }
+        if compressed.ends_with(&DEFLATE_TAIL) {
+            compressed.truncate(compressed.len() - DEFLATE_TAIL.len());
+        }
+        Some(compressed)
+    }
+
+    /// Inflates a compressed inbound message, rejecting it with `EngineError::MessageTooLarge`
+    /// as soon as it inflates to more than `limit` bytes
+    pub fn inflate(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, EngineError> {
+        let input: Vec<u8> = data.iter().chain(&DEFLATE_TAIL).copied().collect();
+        let mut decompress = Decompress::new(false);
+        let mut inflated = Vec::new();
+        loop {
+            // the buffer never grows much past the limit, however much the message inflates
+            let room = (limit - inflated.len())
+                .saturating_add(1)
+                .min(INFLATE_CHUNK);
+            inflated.reserve_exact(room);
+            let (consumed, produced) = (decompress.total_in(), decompress.total_out());
+            let status = decompress
+                .decompress_vec(
+                    &input[consumed as usize..],
+                    &mut inflated,
+                    FlushDecompress::Sync,
+                )
+                .map_err(|e| {
+                    EngineError::Decompression(io::Error::new(ErrorKind::InvalidData, e))
+                })?;
+            if inflated.len() > limit {
+                return Err(EngineError::MessageTooLarge(inflated.len()));
+            }
+            let done = decompress.total_in() as usize == input.len();
+            if status == Status::StreamEnd || (done && inflated.len() < inflated.capacity()) {
+                return Ok(inflated);
+            }
+            if (consumed, produced) == (decompress.total_in(), decompress.total_out()) {
+                return Err(EngineError::Decompression(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "incomplete deflate stream",
+                )));
+            }
+        }
+    }
+
+    /// The frame to send for the message, compressed if it is at least `threshold` bytes
+    fn compress_frame(&self, frame: Frame) -> Frame {
+        let compressed = match &frame {
+            Frame::Text(text) => self.compress(text.as_bytes()).map(|data| (false, data)),
+            Frame::Binary(bytes) => self.compress(bytes).map(|data| (true, data)),
+            _ => None,
+        };
+        match compressed {
+            Some((binary, data)) => Frame::Compressed(Compressed { binary, data }),
+            None => frame,
+        }
+    }
+
+    fn inflate_frame(&self, compressed: Compressed, limit: usize) -> Result<Frame, EngineError> {
+        let data = self.inflate(&compressed.data, limit)?;
+        if compressed.binary {
+            return Ok(Frame::Binary(data));
+        }
+        String::from_utf8(data)
+            .map(Frame::Text)
+            .map_err(|_| TransportParsingError::from(PacketParsingError::NotUtf8).into())
+    }
+}
+
+/// Wraps a `TransportIo` to inflate its compressed inbound messages, and to compress the
+/// outbound messages of at least the threshold of the `Deflate`, if any. A compressed message
+/// received without a `Deflate`, i.e. without negotiating the extension, is rejected.
+#[derive(Debug)]
+pub struct DeflateIo<T> {
+    io: T,
+    deflate: Option<Deflate>,
+    max_message_size: usize,
+}
+
+impl<T: TransportIo> DeflateIo<T> {
+    pub fn new(io: T, deflate: Option<Deflate>, max_message_size: usize) -> DeflateIo<T> {
+        DeflateIo {
+            io,
+            deflate,
+            max_message_size,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+#[async_trait]
+impl<T: TransportIo> TransportIo for DeflateIo<T> {
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>> {
+        let frame = match self.io.recv().await? {
+            Ok(Frame::Compressed(compressed)) => match &self.deflate {
+                Some(deflate) => deflate.inflate_frame(compressed, self.max_message_size),
+                None => Err(EngineError::UnexpectedCompression),
+            },
+            res => res,
+        };
+        Some(frame)
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError> {
+        let frame = match &self.deflate {
+            Some(deflate) => deflate.compress_frame(frame),
+            None => frame,
+        };
+        self.io.send(frame).await
+    }
+
+    async fn close(&mut self) {
+        self.io.close().await
+    }
+
+    fn permessage_deflate(&self) -> bool {
+        self.deflate.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::ChannelIo;
+    use crate::transport::*;
+    use eio_parser::*;
+
+    #[test]
+    fn negotiate_permessage_deflate() {
+        assert!(offers_permessage_deflate(
+            "permessage-deflate; client_max_window_bits"
+        ));
+        assert!(offers_permessage_deflate("foo, permessage-deflate"));
+        assert!(!offers_permessage_deflate("x-webkit-deflate-frame"));
+    }
+
+    #[test]
+    fn compressed_inbound_message_parses() {
+        let deflate = Deflate::new(16);
+        let msg = format!("4{}", "hello ".repeat(10));
+        let compressed = deflate.compress(msg.as_bytes()).unwrap();
+        assert!(compressed.len() < msg.len());
+        let inflated = deflate.inflate(&compressed, 1024).unwrap();
+        let payload = WebsocketTransport::default()
+            .parse_payload(std::str::from_utf8(&inflated).unwrap())
+            .unwrap();
+        assert_eq!(
+            &[Packet::message_text("hello ".repeat(10))],
+            payload.packets()
+        );
+    }
+
+    #[test]
+    fn small_message_not_compressed() {
+        let deflate = Deflate::new(1024);
+        assert_eq!(None, deflate.compress(b"4hello"));
+    }
+
+    #[test]
+    fn compressed_message_ends_with_sync_flush() {
+        let deflate = Deflate::new(0);
+        let msg = "4hello".repeat(100);
+        let compressed = deflate.compress(msg.as_bytes()).unwrap();
+        assert!(!compressed.ends_with(&DEFLATE_TAIL));
+        // without a final block, the stream does not end once the message is inflated
+        let mut input = compressed.clone();
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut decompress = flate2::Decompress::new(false);
+        let mut inflated = Vec::with_capacity(msg.len() * 2);
+        let status = decompress
+            .decompress_vec(&input, &mut inflated, flate2::FlushDecompress::Sync)
+            .unwrap();
+        assert_eq!(flate2::Status::Ok, status);
+        assert_eq!(msg.as_bytes(), &inflated[..]);
+        assert_eq!(
+            msg.as_bytes(),
+            &deflate.inflate(&compressed, msg.len()).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn inflate_limit() {
+        let deflate = Deflate::new(0);
+        let compressed = deflate.compress(&[0; 4096]).unwrap();
+        assert!(matches!(
+            deflate.inflate(&compressed, 1024),
+            Err(EngineError::MessageTooLarge(_))
+        ));
+        assert_eq!(vec![0; 4096], deflate.inflate(&compressed, 4096).unwrap());
+    }
+
+    #[tokio::test]
+    async fn deflate_io() {
+        let (io, mut peer) = ChannelIo::pair();
+        let deflate = Deflate::new(16);
+        let mut io = DeflateIo::new(io, Some(deflate), 1024);
+        assert!(io.permessage_deflate());
+
+        let long = "4hello".repeat(10);
+        io.send(Frame::Text("4hi".to_string())).await.unwrap();
+        io.send(Frame::Text(long.clone())).await.unwrap();
+        io.send(Frame::Binary(vec![1; 64])).await.unwrap();
+        assert_eq!(
+            Some(Frame::Text("4hi".to_string())),
+            peer.recv().await.transpose().unwrap()
+        );
+        for (binary, data) in [(false, long.as_bytes().to_vec()), (true, vec![1; 64])] {
+            match peer.recv().await.transpose().unwrap() {
+                Some(Frame::Compressed(compressed)) => {
+                    assert_eq!(binary, compressed.binary);
+                    assert_eq!(data, deflate.inflate(&compressed.data, 1024).unwrap());
+                }
+                frame => panic!("expected a compressed frame, got {:?}", frame),
+            }
+        }
+
+        let compressed = Compressed {
+            binary: false,
+            data: deflate.compress(long.as_bytes()).unwrap(),
+        };
+        peer.send(Frame::Compressed(compressed.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(Frame::Text(long.clone())),
+            io.recv().await.transpose().unwrap()
+        );
+        // the inflated message is bounded by the max message size
+        let mut small = DeflateIo::new(io.into_inner(), Some(deflate), 8);
+        peer.send(Frame::Compressed(compressed.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            small.recv().await,
+            Some(Err(EngineError::MessageTooLarge(_)))
+        ));
+        // without negotiating the extension, nothing is compressed
+        let mut plain = DeflateIo::new(small.into_inner(), None, 1024);
+        assert!(!plain.permessage_deflate());
+        plain.send(Frame::Text(long.clone())).await.unwrap();
+        assert_eq!(
+            Some(Frame::Text(long)),
+            peer.recv().await.transpose().unwrap()
+        );
+        peer.send(Frame::Compressed(compressed)).await.unwrap();
+        assert!(matches!(
+            plain.recv().await,
+            Some(Err(EngineError::UnexpectedCompression))
+        ));
+    }
+
+    #[test]
+    fn permessage_deflate_in_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(!headers_offer_permessage_deflate(&headers));
+        headers.append(header::SEC_WEBSOCKET_EXTENSIONS, "foo".parse().unwrap());
+        headers.append(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            "permessage-deflate".parse().unwrap(),
+        );
+        assert!(headers_offer_permessage_deflate(&headers));
+    }
+}
@@ -0,0 +1,54 @@
+use axum::http::header::{self, HeaderMap, HeaderValue};
+
+/// The `CorsConfig` struct holds the origins allowed to reach the polling endpoints
+/// from a different origin. By default, no origin is allowed and no CORS headers are sent.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    /// The allowed origins, e.g. "https://example.com", or "*" to allow any origin
+    pub allowed_origins: Vec<String>,
+    pub allow_credentials: bool,
+}
+
+impl CorsConfig {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    /// The CORS headers of a response to a request from the origin,
+    /// which are empty if the origin is missing or not allowed.
+    /// The origin is echoed back, so that credentials can be allowed along with "*".
+    pub fn headers(&self, origin: Option<&HeaderValue>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let origin = match origin {
+            Some(origin) if origin.to_str().is_ok_and(|o| self.allows(o)) => origin,
+            _ => return headers,
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        headers
+    }
+
+    /// The headers of a response to a preflight OPTIONS request
+    pub fn preflight_headers(&self, origin: Option<&HeaderValue>) -> HeaderMap {
+        let mut headers = self.headers(origin);
+        if !headers.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, POST"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("content-type"),
+            );
+        }
+        headers
+    }
+}
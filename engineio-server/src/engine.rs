@@ -1,18 +1,106 @@
+use crate::clock::{Clock, TokioClock};
+use crate::compression::{headers_offer_permessage_deflate, Deflate, DeflateIo};
+use crate::handshake::{AuthRejection, HandshakeConfig, HandshakeRequest};
+use crate::io::{CloseFrame, Frame, ReassemblingIo, TransportIo};
+use crate::metrics::Metrics;
+use crate::session::*;
 use crate::transport::*;
-use axum::extract::ws::{Message, WebSocket};
+use async_trait::async_trait;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::http::{HeaderMap, StatusCode};
 use eio_parser::*;
-use std::fmt::Error;
+use futures::future;
+use rand::Rng;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+const SID_LEN: usize = 20;
+const SID_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum EngineError {
     #[error("Websocket transport expects a valid SID")]
     MissingSIDWebsocket,
+    /// An error of the websocket of `axum::extract::ws`, once the request is upgraded
     #[error("Error establishing websocket connection")]
-    ConnWebsocketErr(#[source] tungstenite::Error),
+    ConnWebsocketErr(#[source] axum::Error),
+    /// The request could not be upgraded to a websocket, e.g. its `Connection` header is missing
+    #[error("Failed to upgrade the request to a websocket")]
+    WebsocketUpgrade(#[from] WebSocketUpgradeRejection),
     #[error("Empty sid given")]
     BlankSID,
+    #[error("Encountered a transport parsing error")]
+    TransportParsingErr(#[from] TransportParsingError),
+    /// A packet queued for the client could not be encoded, e.g. a polling reply
+    /// containing the record separator, which the client would split into two packets
+    #[error("Encountered a transport encoding error")]
+    TransportEncodingErr(#[from] TransportEncodingError),
+    #[error("Client did not respond to ping in time")]
+    PingTimeout,
+    #[error("Session ID unknown")]
+    UnknownSession,
+    #[error("Expected an upgrade packet after the probe")]
+    ExpectedUpgrade,
+    #[error("Transport unknown")]
+    UnknownTransport,
+    #[error("Unsupported protocol version")]
+    UnsupportedProtocolVersion,
+    #[error("Upgrading to the websocket is not allowed")]
+    UpgradeNotAllowed,
+    #[error("The outbound queue of the session is full")]
+    OutboundQueueFull,
+    /// A packet was sent to a session that was terminated, e.g. evicted or closed
+    #[error("The session is closed")]
+    SessionClosed,
+    #[error("The server reached its maximum number of connections")]
+    TooManyConnections,
+    /// A new session was given the sid of an existing one, e.g. by a custom `SidGenerator`
+    #[error("A session already exists for the sid")]
+    SidInUse,
+    #[error("Received a websocket message of {0} bytes, over the maximum message size")]
+    MessageTooLarge(usize),
+    #[error("Received a polling body of {0} bytes, over the maximum HTTP buffer size")]
+    PayloadTooLarge(usize),
+    /// The body of a polling request could not be read, e.g. the client went away
+    #[error("Failed to read the body of the request")]
+    BodyRead(#[source] axum::Error),
+    #[error("Received a websocket fragment out of order")]
+    InvalidFragment,
+    #[error("Failed to inflate a compressed message")]
+    Decompression(#[source] std::io::Error),
+    #[error("Received a compressed message without negotiating permessage-deflate")]
+    UnexpectedCompression,
+}
+
+impl EngineError {
+    /// The engine.io error code sent to the client in the body of a failed request,
+    /// i.e. 0 for "Transport unknown", 1 for "Session ID unknown", 3 for "Bad request"
+    /// and 5 for "Unsupported protocol version"
+    pub fn code(&self) -> u8 {
+        match self {
+            EngineError::UnknownTransport => 0,
+            EngineError::BlankSID | EngineError::UnknownSession => 1,
+            EngineError::UnsupportedProtocolVersion => 5,
+            _ => 3,
+        }
+    }
+
+    /// The status of the response to a failed request, 400 Bad Request unless the server
+    /// is at capacity, which is only temporary, the body is too large, or the server
+    /// generated the sid of an existing session
+    pub fn status(&self) -> StatusCode {
+        match self {
+            EngineError::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+            EngineError::SidInUse => StatusCode::INTERNAL_SERVER_ERROR,
+            EngineError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 /// We will create an engine instance per request.
@@ -25,71 +113,2084 @@ pub enum EngineError {
 pub struct Engine<R: Responder> {
     transport: TransportType,
     responder: R,
-    sid: Option<String>,
+    sessions: SessionStore,
+    session: Option<Arc<Session>>,
+    handshake: HandshakeConfig,
+    compression: Option<Deflate>,
+    max_message_size: usize,
+    clock: Arc<dyn Clock>,
+    per_packet_dispatch: bool,
 }
 
 impl<R: Responder> Engine<R> {
     /// The new function should be used to create a new engine instance,
-    /// usually on the first request of polling transport to establish a connection
-    pub fn new(transport: TransportType, responder: R) -> Engine<R> {
-        Engine {
+    /// usually on the first request of polling transport to establish a connection.
+    /// For the polling transport, a random sid is generated to be passed to the client,
+    /// and a session is created for it in the store.
+    /// For the websocket transport, the client connects without polling first: its session is
+    /// opened as already upgraded, with the Open packet queued to be sent first by `Engine::run`.
+    /// The engine uses the default configuration, see `EngineBuilder` to change it.
+    /// Opening the session fails like `SessionStore::try_insert`, e.g. with
+    /// `EngineError::TooManyConnections` once the store is at its `max_connections`.
+    pub fn new(
+        transport: TransportType,
+        responder: R,
+        sessions: &SessionStore,
+    ) -> Result<Engine<R>, EngineError> {
+        EngineBuilder::new().build(transport, responder, sessions)
+    }
+
+    /// The `with_sid` function can used when upgrading the polling transport to websocket,
+    /// or processing payloads for polling transport. The session of the sid is looked up
+    /// in the store, returning `EngineError::UnknownSession` if it doesn't exist.
+    pub fn with_sid(
+        transport: TransportType,
+        responder: R,
+        sid: Sid,
+        sessions: &SessionStore,
+    ) -> Result<Engine<R>, EngineError> {
+        EngineBuilder::new().build_with_sid(transport, responder, sid, sessions)
+    }
+
+    pub fn sid(&self) -> Option<&str> {
+        self.session.as_ref().map(|s| s.sid().as_str())
+    }
+
+    pub fn session(&self) -> Option<&Arc<Session>> {
+        self.session.as_ref()
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    /// Sets the minimum size of websocket messages to compress with `permessage-deflate`,
+    /// or disables compression entirely with `None`, which is the default
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression = threshold.map(Deflate::new);
+    }
+
+    pub fn compression(&self) -> Option<&Deflate> {
+        self.compression.as_ref()
+    }
+
+    /// The `Sec-WebSocket-Extensions` header to answer the upgrade request with, if compression
+    /// is enabled and the client offered `permessage-deflate` in the headers of the request.
+    /// The socket must then report the extension with `TransportIo::permessage_deflate`.
+    pub fn negotiate_compression(&self, headers: &HeaderMap) -> Option<&'static str> {
+        self.compression
+            .as_ref()
+            .filter(|_| headers_offer_permessage_deflate(headers))
+            .map(Deflate::response_header)
+    }
+
+    /// The maximum size of a websocket message, including all of its fragments
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn handshake_config(&self) -> &HandshakeConfig {
+        &self.handshake
+    }
+
+    /// Builds the Open packet to send to the client on the first polling request
+    pub fn open_packet(&self) -> Option<Packet> {
+        self.session
+            .as_ref()
+            .map(|s| self.handshake.open_packet(s.sid()))
+    }
+
+    pub fn responder(&self) -> &R {
+        &self.responder
+    }
+
+    /// The transport the session is currently on, which is polling until the upgrade completes.
+    /// An engine without a session is on its own transport.
+    pub fn transport_kind(&self) -> TransportKind {
+        match &self.session {
+            Some(session) if session.is_upgraded() => TransportKind::Websocket,
+            Some(_) => TransportKind::Polling,
+            None => self.transport.kind(),
+        }
+    }
+
+    /// The state of the connection, which is `ConnectionState::Closed` for an engine without a session
+    pub fn state(&self) -> ConnectionState {
+        self.session
+            .as_ref()
+            .map_or(ConnectionState::Closed, |s| s.state())
+    }
+
+    /// Whether the client is between the upgrade probe and the upgrade packet
+    pub fn is_upgrading(&self) -> bool {
+        self.state() == ConnectionState::Upgrading
+    }
+
+    /// Terminates the session of the engine. A running websocket sends the Close packet
+    /// and removes the session from the store, while a polling session returns the
+    /// Close packet on its next GET request.
+    pub fn shutdown(&self) {
+        self.close(DisconnectReason::ServerShutdown);
+    }
+
+    /// Closes the connection for the given reason, e.g. `DisconnectReason::PolicyViolation`
+    /// when a client fails to authenticate. A running websocket sends the Close packet,
+    /// followed by a websocket Close frame with the status code of the reason, and stops.
+    /// It takes `&self` so that it can be called while `Engine::run` is running.
+    pub fn close(&self, reason: DisconnectReason) {
+        if let Some(session) = &self.session {
+            session.close(reason);
+        }
+    }
+
+    /// Parses the body of a polling POST request of the session, and dispatches the payload
+    /// to the responder, whose replies are queued for the next GET request.
+    /// With `EngineBuilder::per_packet_dispatch`, each packet is dispatched on its own, in order.
+    /// It lets routers other than `polling_router` serve the polling transport.
+    pub async fn handle_polling_post(&self, sid: &Sid, body: &str) -> Result<(), EngineError> {
+        let session = self.sessions.get(sid)?;
+        let payload = PollingTransport.parse_payload(body)?;
+        let received_at = self.clock.now();
+        let metrics = self.sessions.metrics();
+        metrics.bytes_received(body.len());
+        for packet in &payload {
+            metrics.packet_received(packet.packet_type());
+        }
+        let payloads = if self.per_packet_dispatch {
+            payload
+                .into_iter()
+                .map(|packet| Payload::from_packets(vec![packet]))
+                .collect()
+        } else {
+            vec![payload]
+        };
+        for payload in payloads {
+            let replies = self
+                .responder
+                .process_packet(ResponderPayload::new_at(sid.clone(), payload, received_at))
+                .await;
+            for packet in replies {
+                session.send(packet).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the packets queued for the session, up to `max_payload` bytes, and encodes them into
+    /// the body of a polling GET response. Unlike `polling_router`, it does not wait for a packet
+    /// to be queued, and returns a Noop packet if there is none.
+    /// A packet whose string data contains the record separator fails with
+    /// `EngineError::TransportEncodingErr`.
+    pub async fn handle_polling_get(&self, sid: &Sid) -> Result<Vec<u8>, EngineError> {
+        let session = self.sessions.get(sid)?;
+        let mut packets = session.drain_up_to(self.handshake.max_payload);
+        Ok(self.complete_poll(&session, &mut packets)?.into_bytes())
+    }
+
+    /// Takes the queued packets of the session fitting in a polling payload of `max_bytes`,
+    /// batched as by `PollingTransport::drain_up_to`, without waiting for any. The payload is
+    /// empty if nothing is queued, or if the engine has no session.
+    pub fn drain_outbound(&self, max_bytes: usize) -> Payload {
+        match &self.session {
+            Some(session) => Payload::from_packets(session.take_up_to(max_bytes)),
+            None => Payload::from_packets(Vec::new()),
+        }
+    }
+
+    /// The websocket is any `TransportIo`, e.g. an `axum::extract::ws::WebSocket` wrapped in `AxumIo`.
+    /// While the websocket is open, the engine sends a ping packet every `ping_interval`,
+    /// and closes the socket with `EngineError::PingTimeout` if the client does not respond
+    /// with a pong packet within `ping_timeout`.
+    /// If the session is still on the polling transport, the upgrade handshake is performed first,
+    /// or refused with `EngineError::UpgradeNotAllowed` if "websocket" is not in the `upgrades`.
+    /// When the session is terminated, e.g. by `Engine::shutdown` or `Engine::close`, the Close packet
+    /// and the websocket Close frame are sent, and the session is removed from the store. A payload being processed by the responder completes first,
+    /// since the shutdown is only observed between packets.
+    /// Once the websocket is closed, the responder is told why with `Responder::on_disconnect`.
+    /// Messages split into fragments are joined before being parsed, up to `max_message_size` bytes.
+    /// The websocket of axum joins them itself, so `websocket_router` sets the same limit on the
+    /// upgrade instead.
+    /// If compression is enabled and the socket negotiated `permessage-deflate`, see
+    /// `Engine::negotiate_compression`, the messages over the threshold are compressed and the
+    /// compressed messages of the client are inflated. `AxumIo` never negotiates it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "websocket", skip_all, fields(sid = self.sid()))
+    )]
+    pub async fn run<T: TransportIo>(&self, io: T) -> Result<(), EngineError> {
+        let deflate = self.compression.filter(|_| io.permessage_deflate());
+        let io = DeflateIo::new(io, deflate, self.max_message_size);
+        // fragmented messages are joined before being parsed
+        let mut io = ReassemblingIo::new(io, self.max_message_size);
+        match (&self.transport, &self.session) {
+            // the engines of both `Engine::new` and `Engine::with_sid` have a session
+            (TransportType::Websocket(_), None) => Err(EngineError::MissingSIDWebsocket),
+            (TransportType::Websocket(t), Some(session)) => {
+                if !session.is_upgraded() && !self.handshake.allows_upgrade("websocket") {
+                    // the upgrade is refused without answering the probe
+                    self.sessions.metrics().upgrade(false);
+                    io.close().await;
+                    return Err(EngineError::UpgradeNotAllowed);
+                }
+                if !session.is_upgraded() {
+                    let upgraded = self.upgrade(&mut io, t, session).await;
+                    // either upgraded, or still open on the polling transport
+                    session.set_state(ConnectionState::Open);
+                    let metrics = self.sessions.metrics();
+                    metrics.upgrade(matches!(upgraded, Ok(true)));
+                    if !upgraded? {
+                        // the client closed the socket before completing the upgrade
+                        return Ok(());
+                    }
+                }
+                let res = self.serve(&mut io, t, session).await;
+                let reason = match &res {
+                    Ok(reason) => *reason,
+                    Err(EngineError::PingTimeout) => {
+                        self.sessions.metrics().ping_timeout();
+                        DisconnectReason::PingTimeout
+                    }
+                    Err(_) => DisconnectReason::TransportError,
+                };
+                #[cfg(feature = "tracing")]
+                tracing::info!(reason = %reason, "disconnected");
+                let sid = session.sid();
+                if session.is_terminated() {
+                    self.sessions.remove(sid);
+                } else {
+                    // the session survives the grace period, in case the client reconnects
+                    self.sessions.disconnect(sid);
+                }
+                self.responder.on_disconnect(sid, reason).await;
+                res.map(|_| ())
+            }
+            // the sid for polling is created along with the engine
+            (TransportType::Polling(_), _) => Ok(()),
+        }
+    }
+}
+
+impl<R: Responder> Engine<R> {
+    /// Encodes the packets taken by a polling GET request, or a Noop packet if there is none,
+    /// and records them in the metrics. A terminated session is removed once its Close packet
+    /// is delivered. The packets are checked for the record separator, see `Payload::try_encode`.
+    pub(crate) fn complete_poll(
+        &self,
+        session: &Session,
+        packets: &mut Vec<Packet>,
+    ) -> Result<String, EngineError> {
+        // the Open packet is the first one polled by the client
+        if session.state() == ConnectionState::Opening {
+            session.set_state(ConnectionState::Open);
+        }
+        if packets.is_empty() {
+            packets.push(Packet::noop());
+        }
+        // the client is alive until the end of a long poll, not just at its start
+        session.touch();
+        if session.is_terminated() {
+            self.sessions.remove(session.sid());
+        }
+        let payload = Payload::from_packets(std::mem::take(packets));
+        let encoded = payload.try_encode();
+        packets.extend(payload);
+        let body = encoded.map_err(TransportEncodingError::from)?;
+        let metrics = self.sessions.metrics();
+        for packet in packets.iter() {
+            metrics.packet_sent(packet.packet_type());
+        }
+        metrics.bytes_sent(body.len());
+        Ok(body)
+    }
+
+    /// Encodes and sends a packet over the websocket, recording it in the metrics
+    async fn send_packet<T: TransportIo>(
+        &self,
+        io: &mut T,
+        transport: &WebsocketTransport,
+        packet: &Packet,
+    ) -> Result<(), EngineError> {
+        let frame = transport.encode_packet(packet);
+        let metrics = self.sessions.metrics();
+        metrics.packet_sent(packet.packet_type());
+        metrics.bytes_sent(frame.len());
+        io.send(frame).await
+    }
+
+    /// Serves the upgraded websocket until it is closed, returning why it was closed
+    async fn serve<T: TransportIo>(
+        &self,
+        io: &mut T,
+        t: &WebsocketTransport,
+        session: &Session,
+    ) -> Result<DisconnectReason, EngineError> {
+        let sid = session.sid();
+        let clock = self.clock.as_ref();
+        let ping_interval = self.handshake.ping_interval;
+        let mut next_ping = clock.now() + ping_interval;
+        let mut pong_deadline: Option<Instant> = None;
+        loop {
+            let pong_timeout = async {
+                match pong_deadline {
+                    Some(deadline) => clock.sleep_until(deadline).await,
+                    None => future::pending().await,
+                }
+            };
+            let frame = tokio::select! {
+                _ = clock.sleep_until(next_ping) => {
+                    next_ping += ping_interval;
+                    let ping = Packet::ping();
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!("sending ping");
+                    self.send_packet(io, t, &ping).await?;
+                    if pong_deadline.is_none() {
+                        pong_deadline = Some(clock.now() + self.handshake.ping_timeout);
+                    }
+                    continue;
+                }
+                _ = pong_timeout => {
+                    io.close().await;
+                    return Err(EngineError::PingTimeout);
+                }
+                packets = session.recv() => {
+                    for packet in packets {
+                        self.send_packet(io, t, &packet).await?;
+                    }
+                    continue;
+                }
+                _ = session.terminated() => {
+                    let reason = session.close_reason().unwrap_or(DisconnectReason::ServerShutdown);
+                    self.send_packet(io, t, &Packet::close()).await?;
+                    let close = CloseFrame {
+                        code: reason.close_code(),
+                        reason: reason.to_string(),
+                    };
+                    io.send(Frame::Close(Some(close))).await?;
+                    io.close().await;
+                    return Ok(reason);
+                }
+                frame = io.recv() => match frame {
+                    Some(frame) => frame?,
+                    None => return Ok(DisconnectReason::TransportClose),
+                },
+            };
+            let payload = match parse_frame(t, frame, self.sessions.metrics())? {
+                Some(payload) => payload,
+                None => return Ok(DisconnectReason::ClientClose),
+            };
+            let received_at = self.clock.now();
+            // a frame split by a lenient transport is processed one packet at a time
+            for packet in payload {
+                // pongs only keep the connection alive, so they are not passed to the responder
+                if packet.is_heartbeat_pong() {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!("received pong");
+                    pong_deadline = None;
+                    continue;
+                }
+                check_client_packet(&packet)?;
+                let payload =
+                    ResponderPayload::new_at(sid.clone(), Payload::single(packet), received_at);
+                let replies = self.responder.process_packet(payload).await;
+                // the replies are sent right away, as this loop is what drains the outbound queue
+                for packet in replies {
+                    self.send_packet(io, t, &packet).await?;
+                }
+            }
+        }
+    }
+
+    /// Upgrades the session from polling to the websocket. The client first sends a ping
+    /// packet with data "probe", to which the server replies with a pong packet with data "probe".
+    /// A Noop packet is also queued on the polling transport, so that a GET request held open
+    /// returns right away, as the client waits for it before sending the upgrade packet.
+    /// The packets still buffered for the polling transport are then flushed to the websocket,
+    /// except for that Noop. Returns false if the socket is closed during the upgrade.
+    async fn upgrade<T: TransportIo>(
+        &self,
+        io: &mut T,
+        transport: &WebsocketTransport,
+        session: &Session,
+    ) -> Result<bool, EngineError> {
+        match next_packet(io, transport, self.sessions.metrics()).await? {
+            Some(p) if p.is_probe() && p.packet_type() == &PacketType::Ping => {}
+            Some(_) => {
+                return Err(TransportParsingError::from(PacketParsingError::InvalidPing).into())
+            }
+            None => return Ok(false),
+        }
+        session.set_state(ConnectionState::Upgrading);
+        let probe = Packet::pong_probe();
+        self.send_packet(io, transport, &probe).await?;
+        session.send_control(Packet::noop());
+        match next_packet(io, transport, self.sessions.metrics()).await? {
+            Some(p) if p.packet_type() == &PacketType::Upgrade => {}
+            Some(_) => return Err(EngineError::ExpectedUpgrade),
+            None => return Ok(false),
+        }
+        for packet in session.drain() {
+            if packet.packet_type() != &PacketType::Noop {
+                self.send_packet(io, transport, &packet).await?;
+            }
+        }
+        session.mark_upgraded();
+        #[cfg(feature = "tracing")]
+        tracing::info!("upgraded to websocket");
+        Ok(true)
+    }
+}
+
+/// The default minimum size of the websocket messages compressed when compression is enabled
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The `EngineBuilder` configures the connection, then produces an `Engine`.
+/// The defaults follow the recommendations of the V4 protocol.
+#[derive(Debug, Clone, Default)]
+pub struct EngineBuilder {
+    handshake: HandshakeConfig,
+    compression: Option<Deflate>,
+    sid_generator: Option<Arc<dyn SidGenerator>>,
+    max_message_size: Option<usize>,
+    clock: Option<Arc<dyn Clock>>,
+    per_packet_dispatch: bool,
+}
+
+impl EngineBuilder {
+    pub fn new() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    pub fn ping_interval(mut self, ping_interval: Duration) -> EngineBuilder {
+        self.handshake.ping_interval = ping_interval;
+        self
+    }
+
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> EngineBuilder {
+        self.handshake.ping_timeout = ping_timeout;
+        self
+    }
+
+    pub fn max_payload(mut self, max_payload: usize) -> EngineBuilder {
+        self.handshake.max_payload = max_payload;
+        self
+    }
+
+    pub fn upgrades(mut self, upgrades: Vec<String>) -> EngineBuilder {
+        self.handshake.upgrades = upgrades;
+        self
+    }
+
+    /// Replaces the whole handshake configuration, e.g. one shared with the polling endpoints
+    pub fn handshake(mut self, handshake: HandshakeConfig) -> EngineBuilder {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Enables or disables the `permessage-deflate` compression of websocket messages, which
+    /// applies to the sockets negotiating it, see `Engine::negotiate_compression`.
+    /// The websocket of axum cannot, so it only applies to custom `TransportIo` adapters.
+    pub fn compression(mut self, enabled: bool) -> EngineBuilder {
+        self.compression = enabled.then(|| Deflate::new(DEFAULT_COMPRESSION_THRESHOLD));
+        self
+    }
+
+    /// Enables compression for the websocket messages of at least `threshold` bytes
+    pub fn compression_threshold(mut self, threshold: usize) -> EngineBuilder {
+        self.compression = Some(Deflate::new(threshold));
+        self
+    }
+
+    /// Sets the maximum size of a websocket message, whose fragments are buffered until
+    /// it is complete. It defaults to the `max_payload` of the handshake.
+    pub fn max_message_size(mut self, max_message_size: usize) -> EngineBuilder {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// Sets the clock of the heartbeat, e.g. a `MockClock` in tests. It is `TokioClock` by default.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> EngineBuilder {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Dispatches each packet of a polling POST to the responder in its own `ResponderPayload`,
+    /// in the order of the payload, rather than the whole payload at once
+    pub fn per_packet_dispatch(mut self, enabled: bool) -> EngineBuilder {
+        self.per_packet_dispatch = enabled;
+        self
+    }
+
+    /// Sets the generator of the sids of new polling sessions, `RandomSidGenerator` by default.
+    /// The polling endpoints take theirs from `PollingConfig::sid_generator`.
+    pub fn sid_generator(mut self, generator: impl SidGenerator + 'static) -> EngineBuilder {
+        self.sid_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// Builds the engine for a new connection, see `Engine::new`
+    pub fn build<R: Responder>(
+        self,
+        transport: TransportType,
+        responder: R,
+        sessions: &SessionStore,
+    ) -> Result<Engine<R>, EngineError> {
+        let sid = match &self.sid_generator {
+            Some(generator) => generate_sid(generator.as_ref())?,
+            None => generate_sid(&RandomSidGenerator)?,
+        };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("handshake", sid = sid.as_str()).entered();
+        let session = sessions.try_insert(sid)?;
+        match transport {
+            TransportType::Polling(_) => {
+                #[cfg(feature = "tracing")]
+                tracing::info!("opened polling session");
+            }
+            TransportType::Websocket(_) => {
+                #[cfg(feature = "tracing")]
+                tracing::info!("opened websocket session");
+                session.mark_upgraded();
+                session.set_state(ConnectionState::Open);
+                session.send_control(self.handshake.open_packet(session.sid()));
+            }
+        }
+        let session = Some(session);
+        Ok(Engine {
             transport,
             responder,
-            sid: None,
-        }
+            sessions: sessions.clone(),
+            session,
+            max_message_size: self.max_message_size.unwrap_or(self.handshake.max_payload),
+            handshake: self.handshake,
+            compression: self.compression,
+            clock: self.clock.unwrap_or_else(|| Arc::new(TokioClock)),
+            per_packet_dispatch: self.per_packet_dispatch,
+        })
     }
 
-    /// The `with_sid` function can used when upgrading the polling transport to websocket,
-    /// or processing payloads for polling transport.
-    pub fn with_sid(transport: TransportType, responder: R, sid: String) -> Engine<R> {
-        Engine {
+    /// Builds the engine for an existing session, see `Engine::with_sid`
+    pub fn build_with_sid<R: Responder>(
+        self,
+        transport: TransportType,
+        responder: R,
+        sid: Sid,
+        sessions: &SessionStore,
+    ) -> Result<Engine<R>, EngineError> {
+        let session = sessions.get(&sid)?;
+        Ok(Engine {
             transport,
             responder,
-            sid: Some(sid),
+            sessions: sessions.clone(),
+            session: Some(session),
+            max_message_size: self.max_message_size.unwrap_or(self.handshake.max_payload),
+            handshake: self.handshake,
+            compression: self.compression,
+            clock: self.clock.unwrap_or_else(|| Arc::new(TokioClock)),
+            per_packet_dispatch: self.per_packet_dispatch,
+        })
+    }
+}
+
+/// Reads the next packet from the websocket. Returns `None` when the socket is closed.
+async fn next_packet<T: TransportIo>(
+    io: &mut T,
+    transport: &WebsocketTransport,
+    metrics: &dyn Metrics,
+) -> Result<Option<Packet>, EngineError> {
+    match io.recv().await {
+        Some(frame) => {
+            Ok(parse_frame(transport, frame?, metrics)?.and_then(|p| p.into_iter().next()))
         }
+        None => Ok(None),
     }
+}
 
-    /// Currently the engine only works with axum. Assume that we get `mut axum::extract::ws::WebSocket`
-    async fn run(&self, mut socket: WebSocket) -> Result<(), EngineError> {
-        match (&self.transport, &self.sid) {
-            // clients must go through the upgrade process from polling,
-            // which means that they should already have an sid
-            (TransportType::Websocket(t), None) => Err(EngineError::MissingSIDWebsocket),
-            (TransportType::Websocket(t), Some(sid)) => self.responder(),
-            // create an sid and pass it the client
-            (TransportType::Polling(t), None) => Ok(()),
-            (TransportType::Polling(t), Some(sid)) => Ok(()),
+/// Parses a text or binary websocket frame into a payload.
+/// Returns `None` for the Close frame.
+fn parse_frame(
+    transport: &WebsocketTransport,
+    frame: Frame,
+    metrics: &dyn Metrics,
+) -> Result<Option<Payload>, EngineError> {
+    let len = frame.len();
+    let payload = match frame {
+        // a bare heartbeat pong, the most frequent frame, skips the full parse
+        Frame::Text(text)
+            if text.len() == 1 && peek_packet_type(&text) == Some(PacketType::Pong) =>
+        {
+            Payload::single(Packet::pong())
         }
+        Frame::Text(text) => transport.parse_payload(&text)?,
+        // binary frames carry the raw bytes of a binary message, without base64
+        Frame::Binary(bytes) => transport.parse_binary(bytes),
+        Frame::Close(_) => return Ok(None),
+        // fragments are reassembled before reaching the engine
+        Frame::Fragment(_) => return Err(EngineError::InvalidFragment),
+        // and compressed messages inflated
+        Frame::Compressed(_) => return Err(EngineError::UnexpectedCompression),
+    };
+    metrics.bytes_received(len);
+    for packet in &payload {
+        metrics.packet_received(packet.packet_type());
     }
+    #[cfg(feature = "tracing")]
+    for packet in &payload {
+        tracing::debug!(
+            packet_type = ?packet.packet_type(),
+            len = packet.byte_len(TransportKind::Websocket),
+            "parsed packet"
+        );
+    }
+    Ok(Some(payload))
 }
 
 /// The struct `Sid` represents a valid sid, which is simply a non-empty one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Sid(String);
 
 impl Sid {
     pub fn new(sid: String) -> Result<Sid, EngineError> {
-        if sid.len() != 0 {
+        if !sid.is_empty() {
             Ok(Sid(sid))
         } else {
             Err(EngineError::BlankSID)
         }
     }
+
+    /// Generates a random sid of 20 characters from the base64url alphabet
+    pub fn generate() -> Sid {
+        let mut rng = rand::thread_rng();
+        let sid = (0..SID_LEN)
+            .map(|_| SID_ALPHABET[rng.gen_range(0..SID_ALPHABET.len())] as char)
+            .collect();
+        Sid(sid)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Sid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The trait `SidGenerator` creates the sid of each new session, e.g. to embed a shard id.
+/// A generator builds its sids with `Sid::new`, and the engine checks them again when opening
+/// a session, so an empty sid is rejected with `EngineError::BlankSID` either way.
+pub trait SidGenerator: fmt::Debug + Send + Sync {
+    fn generate(&self) -> Sid;
+}
+
+/// Generates the sid of a new session, without trusting the generator to return a valid one
+pub(crate) fn generate_sid(generator: &dyn SidGenerator) -> Result<Sid, EngineError> {
+    let sid = generator.generate();
+    if sid.as_str().is_empty() {
+        return Err(EngineError::BlankSID);
+    }
+    Ok(sid)
+}
+
+/// The default generator, creating random sids with `Sid::generate`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomSidGenerator;
+
+impl SidGenerator for RandomSidGenerator {
+    fn generate(&self) -> Sid {
+        Sid::generate()
+    }
 }
 
-/// A ResponderPayload struct contains the sid and payload delivered by the client.
+/// A ResponderPayload struct contains the sid and payload delivered by the client,
+/// along with when it was received, e.g. to measure the latency of the responder.
 #[derive(Debug, Clone)]
 pub struct ResponderPayload {
     pub payload: Payload,
     pub sid: Sid,
+    received_at: Instant,
 }
 
 impl ResponderPayload {
+    /// Wraps the payload as is, received now, see `try_new` to check the packets sent by a client
     pub fn new(sid: Sid, payload: Payload) -> ResponderPayload {
-        ResponderPayload { payload, sid }
+        ResponderPayload::new_at(sid, payload, Instant::now())
+    }
+
+    /// Same as `new`, with the instant the payload was received, e.g. by the clock of the engine
+    pub fn new_at(sid: Sid, payload: Payload, received_at: Instant) -> ResponderPayload {
+        ResponderPayload {
+            payload,
+            sid,
+            received_at,
+        }
+    }
+
+    /// When the engine parsed the payload
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Same as `new`, but fails if the payload holds a packet a client is not allowed to send,
+    /// e.g. a Noop packet, which is only sent by the server
+    pub fn try_new(sid: Sid, payload: Payload) -> Result<ResponderPayload, TransportParsingError> {
+        payload.iter().try_for_each(check_client_packet)?;
+        Ok(ResponderPayload::new(sid, payload))
+    }
+}
+
+/// The reason a websocket connection was closed, passed to `Responder::on_disconnect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The socket was closed without a Close frame, e.g. the connection dropped
+    TransportClose,
+    /// The socket failed, or the client sent a message that could not be parsed
+    TransportError,
+    /// The client did not respond to a ping within `ping_timeout`
+    PingTimeout,
+    /// The client sent a Close frame
+    ClientClose,
+    /// The session was terminated by the server, e.g. with `Engine::shutdown`
+    ServerShutdown,
+    /// The server closed the connection as the client broke a policy, e.g. failed to authenticate
+    PolicyViolation,
+}
+
+impl DisconnectReason {
+    /// The status code of the websocket Close frame sent when the server closes for this reason
+    pub fn close_code(&self) -> u16 {
+        match self {
+            DisconnectReason::TransportClose
+            | DisconnectReason::ClientClose
+            | DisconnectReason::PingTimeout => 1000,
+            DisconnectReason::ServerShutdown => 1001,
+            DisconnectReason::TransportError => 1002,
+            DisconnectReason::PolicyViolation => 1008,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisconnectReason::TransportClose => "transport closed",
+            DisconnectReason::TransportError => "transport error",
+            DisconnectReason::PingTimeout => "ping timeout",
+            DisconnectReason::ClientClose => "client close",
+            DisconnectReason::ServerShutdown => "server shutdown",
+            DisconnectReason::PolicyViolation => "policy violation",
+        })
+    }
+}
+
+/// The trait Responder is responsible for processing each payload.
+/// It takes `&self` so that implementors can hold state, e.g. a database pool or a channel.
+/// The returned packets are sent back to the client, in order, and an empty vec sends nothing.
+#[async_trait]
+pub trait Responder: Send + Sync {
+    async fn process_packet(&self, packet: ResponderPayload) -> Vec<Packet>;
+
+    /// Called once the websocket of the session is closed, does nothing by default
+    async fn on_disconnect(&self, _sid: &Sid, _reason: DisconnectReason) {}
+
+    /// Called before a session is opened by the polling handshake, and before a websocket
+    /// upgrade is accepted by `websocket_router`, e.g. to check a token. Every request is
+    /// accepted by default.
+    async fn authenticate(&self, _req: &HandshakeRequest) -> Result<(), AuthRejection> {
+        Ok(())
+    }
+}
+
+/// A shared responder, so that the engines created for each polling request can use the same one
+#[async_trait]
+impl<R: Responder> Responder for Arc<R> {
+    async fn process_packet(&self, packet: ResponderPayload) -> Vec<Packet> {
+        self.as_ref().process_packet(packet).await
+    }
+
+    async fn on_disconnect(&self, sid: &Sid, reason: DisconnectReason) {
+        self.as_ref().on_disconnect(sid, reason).await
+    }
+
+    async fn authenticate(&self, req: &HandshakeRequest) -> Result<(), AuthRejection> {
+        self.as_ref().authenticate(req).await
     }
 }
 
-/// The trait Responder is responsible for processing each payload
-pub trait Responder {
-    fn process_packet(packet: ResponderPayload);
+/// The `ChannelResponder` forwards each payload to a channel, for applications processing the
+/// payloads of all the sessions in one place, e.g. an actor. It replies nothing itself, replies are
+/// queued with `Session::send` instead. A full channel holds the engine back, and the payloads
+/// are dropped once the receiver is gone.
+#[derive(Debug, Clone)]
+pub struct ChannelResponder {
+    tx: mpsc::Sender<ResponderPayload>,
+}
+
+impl ChannelResponder {
+    pub fn new(tx: mpsc::Sender<ResponderPayload>) -> ChannelResponder {
+        ChannelResponder { tx }
+    }
+}
+
+#[async_trait]
+impl Responder for ChannelResponder {
+    async fn process_packet(&self, packet: ResponderPayload) -> Vec<Packet> {
+        if self.tx.send(packet).await.is_err() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!("dropped a payload, the receiver of the channel is gone");
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::io::{ChannelIo, Compressed, Fragment, FragmentKind};
+    use crate::metrics::InMemoryMetrics;
+    use crate::test_support::MockWebSocket;
+    use futures::FutureExt;
+    use std::collections::{HashMap, HashSet};
+    use tokio::sync::mpsc;
+
+    /// Reads the next frame sent by the engine, or `None` once it closed the connection
+    async fn next_frame(peer: &mut ChannelIo) -> Option<Frame> {
+        peer.recv().await.transpose().unwrap()
+    }
+
+    struct NoopResponder;
+
+    #[async_trait]
+    impl Responder for NoopResponder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![]
+        }
+    }
+
+    #[tokio::test]
+    async fn responder_forwards_to_channel() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let engine = Engine::new(
+            TransportType::Polling(PollingTransport),
+            ChannelResponder::new(tx),
+            &SessionStore::new(),
+        )
+        .unwrap();
+        let sid = engine.session().unwrap().sid().clone();
+        engine.handle_polling_post(&sid, "4hello").await.unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!(sid, received.sid);
+        assert_eq!(Payload::try_from("4hello").unwrap(), received.payload);
+        // nothing is replied to the client
+        assert_eq!(
+            b"6".to_vec(),
+            engine.handle_polling_get(&sid).await.unwrap()
+        );
+
+        // once the receiver is gone, the payloads are dropped
+        drop(rx);
+        engine.handle_polling_post(&sid, "4bye").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn responder_payload_received_at() {
+        let sid = Sid::generate();
+        let payload = Payload::try_from("4hello").unwrap();
+        let received_at = Instant::now() - Duration::from_secs(1);
+        let timed = ResponderPayload::new_at(sid.clone(), payload.clone(), received_at);
+        assert_eq!(received_at, timed.received_at());
+        let before = Instant::now();
+        let now = ResponderPayload::new(sid, payload);
+        assert!(before <= now.received_at() && now.received_at() <= Instant::now());
+
+        // the engine stamps the payloads with its clock when it parses them
+        let clock = Arc::new(MockClock::new());
+        let (tx, mut rx) = mpsc::channel(1);
+        let engine = EngineBuilder::new()
+            .clock(clock.clone())
+            .build(
+                TransportType::Polling(PollingTransport),
+                ChannelResponder::new(tx),
+                &SessionStore::new(),
+            )
+            .unwrap();
+        let sid = engine.session().unwrap().sid().clone();
+        clock.advance(Duration::from_secs(5));
+        engine.handle_polling_post(&sid, "4hello").await.unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!(clock.now(), received.received_at());
+    }
+
+    #[tokio::test]
+    async fn drain_outbound_within_budget() {
+        let engine = Engine::new(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            &SessionStore::new(),
+        )
+        .unwrap();
+        let session = engine.session().unwrap();
+        for text in ["4aaa", "4bbb", "4ccc"] {
+            session.send(Packet::try_from(text).unwrap()).await.unwrap();
+        }
+        // "4aaa\x1e4bbb" is 9 bytes, and the third packet would need 5 more
+        assert_eq!(
+            Payload::try_from("4aaa\x1e4bbb").unwrap(),
+            engine.drain_outbound(9)
+        );
+        // the first packet is taken even if it alone is over the budget
+        assert_eq!(Payload::try_from("4ccc").unwrap(), engine.drain_outbound(1));
+        assert!(engine.drain_outbound(usize::MAX).is_empty());
+    }
+
+    /// The messages of the error and its sources, as shown by error reporters such as anyhow
+    fn source_chain(err: &(dyn std::error::Error + 'static)) -> Vec<String> {
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        chain
+    }
+
+    #[test]
+    fn engine_error_source_chain() {
+        let io_err = || std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let err = EngineError::ConnWebsocketErr(axum::Error::new(io_err()));
+        assert_eq!(
+            vec!["Error establishing websocket connection", "reset", "reset"],
+            source_chain(&err)
+        );
+        // the axum error wraps the error of the socket
+        let source = std::error::Error::source(&err).unwrap();
+        let axum_err = source.downcast_ref::<axum::Error>().unwrap();
+        let inner = std::error::Error::source(axum_err).unwrap();
+        assert_eq!(
+            Some(std::io::ErrorKind::ConnectionReset),
+            inner.downcast_ref::<std::io::Error>().map(|e| e.kind())
+        );
+
+        let err = EngineError::Decompression(io_err());
+        assert_eq!(
+            vec!["Failed to inflate a compressed message", "reset"],
+            source_chain(&err)
+        );
+
+        // a packet of a payload is reported down to why it failed to parse
+        let err = EngineError::from(
+            PollingTransport
+                .parse_payload("4hello\x1e2foo")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            vec![
+                "Encountered a transport parsing error",
+                "Encountered a packet parsing error",
+                "invalid packet at index 1 of payload",
+                "invalid ping packet",
+            ],
+            source_chain(&err)
+        );
+        assert_eq!(
+            vec!["Session ID unknown"],
+            source_chain(&EngineError::UnknownSession)
+        );
+    }
+
+    #[test]
+    fn polling_engine_generates_sid() {
+        let mut sids = HashSet::new();
+        for _ in 0..1000 {
+            let engine = Engine::new(
+                TransportType::Polling(PollingTransport),
+                NoopResponder,
+                &SessionStore::new(),
+            )
+            .unwrap();
+            let sid = engine.sid().unwrap();
+            assert_eq!(SID_LEN, sid.len());
+            assert!(sids.insert(sid.to_string()));
+        }
+    }
+
+    #[test]
+    fn polling_engine_open_packet() {
+        let engine = Engine::new(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            &SessionStore::new(),
+        )
+        .unwrap();
+        let packet = engine.open_packet().unwrap();
+        let encoded = packet.to_string();
+        let handshake: serde_json::Value = serde_json::from_str(&encoded[1..]).unwrap();
+        assert_eq!(engine.sid().unwrap(), handshake["sid"]);
+    }
+
+    /// Creates a websocket engine for a session that is already upgraded
+    fn upgraded_websocket_engine() -> Engine<NoopResponder> {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_sends_pings_on_schedule() {
+        let engine = upgraded_websocket_engine();
+        let (io, mut peer) = ChannelIo::pair();
+        let ping_interval = engine.handshake_config().ping_interval;
+        let client = async move {
+            let start = Instant::now();
+            for i in 1..=3 {
+                let msg = next_frame(&mut peer).await.unwrap();
+                assert_eq!(Frame::Text("2".to_string()), msg);
+                assert_eq!(ping_interval * i, start.elapsed());
+                peer.send(Frame::Text("3".to_string())).await.unwrap();
+            }
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_missing_pong_times_out() {
+        let engine = upgraded_websocket_engine();
+        let (io, mut peer) = ChannelIo::pair();
+        let start = Instant::now();
+        let res = engine.run(io).await;
+        assert!(matches!(res, Err(EngineError::PingTimeout)));
+        let config = engine.handshake_config();
+        assert_eq!(config.ping_interval + config.ping_timeout, start.elapsed());
+        assert_eq!(
+            Some(Frame::Text("2".to_string())),
+            next_frame(&mut peer).await
+        );
+        // the socket is closed after the ping
+        assert_eq!(None, next_frame(&mut peer).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_heartbeat_only_connection() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let ping_interval = Duration::from_secs(1);
+        let ping_timeout = Duration::from_millis(500);
+        let engine = EngineBuilder::new()
+            .ping_interval(ping_interval)
+            .ping_timeout(ping_timeout)
+            .build_with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                session.sid().clone(),
+                &sessions,
+            )
+            .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        let start = Instant::now();
+        let client = async move {
+            // the client answers the first three pings, but not the fourth
+            for i in 1..=4 {
+                let msg = next_frame(&mut peer).await.unwrap();
+                assert_eq!(Frame::Text("2".to_string()), msg);
+                assert_eq!(ping_interval * i, start.elapsed());
+                if i < 4 {
+                    peer.send(Frame::Text("3".to_string())).await.unwrap();
+                }
+            }
+            peer
+        };
+        let (res, _client) = tokio::join!(engine.run(io), client);
+        assert!(matches!(res, Err(EngineError::PingTimeout)));
+        assert_eq!(ping_interval * 4 + ping_timeout, start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        let hello = Packet::message_text("hello");
+        session.send(hello.clone()).await.unwrap();
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async move {
+            peer.send(Frame::Text("2probe".to_string())).await.unwrap();
+            assert_eq!(
+                Some(Frame::Text("3probe".to_string())),
+                next_frame(&mut peer).await
+            );
+            assert!(!session.is_upgraded());
+            peer.send(Frame::Text("5".to_string())).await.unwrap();
+            // the packets buffered for polling are flushed to the websocket
+            assert_eq!(
+                Some(Frame::Text(hello.to_string())),
+                next_frame(&mut peer).await
+            );
+            assert!(session.is_upgraded());
+            peer.send(Frame::Close(None)).await.unwrap();
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_invalid_probe() {
+        let cases = [
+            (
+                "2foo",
+                PacketParsingError::InvalidPayloadPacket {
+                    index: 0,
+                    source: Box::new(PacketParsingError::InvalidPing),
+                },
+            ),
+            ("4hello", PacketParsingError::InvalidPing),
+        ];
+        for (probe, expected) in cases {
+            let sessions = SessionStore::new();
+            let sid = sessions.insert(Sid::generate()).sid().clone();
+            let engine = Engine::with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                sid,
+                &sessions,
+            )
+            .unwrap();
+            let (io, mut peer) = ChannelIo::pair();
+            peer.send(Frame::Text(probe.to_string())).await.unwrap();
+            match engine.run(io).await {
+                Err(EngineError::TransportParsingErr(TransportParsingError::PacketParsingErr(
+                    err,
+                ))) => assert_eq!(expected, err),
+                res => panic!("expected an invalid ping, got {:?}", res),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_engines_share_session() {
+        let sessions = SessionStore::new();
+        let first = Engine::new(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            &sessions,
+        )
+        .unwrap();
+        let sid = first.session().unwrap().sid().clone();
+        let second = Engine::with_sid(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            sid,
+            &sessions,
+        )
+        .unwrap();
+        let packet = Packet::noop();
+        second
+            .session()
+            .unwrap()
+            .send(packet.clone())
+            .await
+            .unwrap();
+        assert_eq!(vec![packet], first.session().unwrap().recv().await);
+    }
+
+    #[test]
+    fn unknown_session() {
+        let res = Engine::with_sid(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            Sid::generate(),
+            &SessionStore::new(),
+        );
+        assert!(matches!(res, Err(EngineError::UnknownSession)));
+    }
+
+    /// Replies to each message with the message twice
+    struct EchoTwiceResponder;
+
+    #[async_trait]
+    impl Responder for EchoTwiceResponder {
+        async fn process_packet(&self, packet: ResponderPayload) -> Vec<Packet> {
+            packet
+                .payload
+                .into_iter()
+                .filter(|p| p.packet_type() == &PacketType::Message)
+                .flat_map(|p| [p.clone(), p])
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_sends_responder_replies() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            EchoTwiceResponder,
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async move {
+            for msg in ["4hello", "4world"] {
+                peer.send(Frame::Text(msg.to_string())).await.unwrap();
+            }
+            for msg in ["4hello", "4hello", "4world", "4world"] {
+                assert_eq!(
+                    Some(Frame::Text(msg.to_string())),
+                    next_frame(&mut peer).await
+                );
+            }
+            peer.send(Frame::Close(None)).await.unwrap();
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn polling_handlers() {
+        let sessions = SessionStore::new();
+        let engine = EngineBuilder::new()
+            .max_payload(13)
+            .build(
+                TransportType::Polling(PollingTransport),
+                EchoTwiceResponder,
+                &sessions,
+            )
+            .unwrap();
+        let sid = engine.session().unwrap().sid().clone();
+
+        engine.handle_polling_post(&sid, "4hello").await.unwrap();
+        engine.handle_polling_post(&sid, "4bye").await.unwrap();
+        // the replies beyond the max payload are left for the next request
+        assert_eq!(
+            b"4hello\x1e4hello".to_vec(),
+            engine.handle_polling_get(&sid).await.unwrap()
+        );
+        assert_eq!(
+            b"4bye\x1e4bye".to_vec(),
+            engine.handle_polling_get(&sid).await.unwrap()
+        );
+        assert_eq!(
+            b"6".to_vec(),
+            engine.handle_polling_get(&sid).await.unwrap()
+        );
+
+        assert!(matches!(
+            engine.handle_polling_post(&sid, "9").await,
+            Err(EngineError::TransportParsingErr(_))
+        ));
+        let unknown = Sid::new("unknown".to_string()).unwrap();
+        assert!(matches!(
+            engine.handle_polling_post(&unknown, "4hello").await,
+            Err(EngineError::UnknownSession)
+        ));
+        assert!(matches!(
+            engine.handle_polling_get(&unknown).await,
+            Err(EngineError::UnknownSession)
+        ));
+
+        // the session is removed once its Close packet is delivered
+        engine.shutdown();
+        assert_eq!(
+            b"1".to_vec(),
+            engine.handle_polling_get(&sid).await.unwrap()
+        );
+        assert!(sessions.is_empty());
+    }
+
+    /// Replies to each payload with a message containing the record separator
+    struct SeparatorResponder;
+
+    #[async_trait]
+    impl Responder for SeparatorResponder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![Packet::message_text("a\x1e4b")]
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_reply_with_separator() {
+        let sessions = SessionStore::new();
+        let engine = Engine::new(
+            TransportType::Polling(PollingTransport),
+            SeparatorResponder,
+            &sessions,
+        )
+        .unwrap();
+        let sid = engine.session().unwrap().sid().clone();
+
+        engine.handle_polling_post(&sid, "4hello").await.unwrap();
+        // the reply is refused rather than read by the client as the packets "4a" and "4b"
+        assert!(matches!(
+            engine.handle_polling_get(&sid).await,
+            Err(EngineError::TransportEncodingErr(
+                TransportEncodingError::PacketEncodingErr(PacketEncodingError::SeparatorInPacket(
+                    0
+                ))
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn websocket_mock_clock_heartbeat() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let clock = Arc::new(MockClock::new());
+        let engine = EngineBuilder::new()
+            .clock(clock.clone())
+            .build_with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                session.sid().clone(),
+                &sessions,
+            )
+            .unwrap();
+        let config = engine.handshake_config().clone();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async {
+            // the engine only observes the mock clock, which has not moved yet
+            tokio::task::yield_now().await;
+            clock.advance(config.ping_interval - Duration::from_millis(1));
+            tokio::task::yield_now().await;
+            assert!(peer.recv().now_or_never().is_none());
+
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(
+                Some(Frame::Text(Packet::ping().to_string())),
+                next_frame(&mut peer).await
+            );
+            clock.advance(config.ping_timeout);
+            assert_eq!(None, next_frame(&mut peer).await);
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(matches!(res, Err(EngineError::PingTimeout)));
+    }
+
+    #[tokio::test]
+    async fn websocket_reassembles_fragments() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let (tx, mut rx) = mpsc::channel(1);
+        let engine = EngineBuilder::new()
+            .max_message_size(6)
+            .build_with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                ChannelResponder::new(tx),
+                session.sid().clone(),
+                &sessions,
+            )
+            .unwrap();
+        assert_eq!(6, engine.max_message_size());
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async move {
+            for (kind, data, fin) in [
+                (FragmentKind::Binary, vec![1, 2], false),
+                (FragmentKind::Continuation, vec![3, 4], false),
+                (FragmentKind::Continuation, vec![5], true),
+            ] {
+                let fragment = Fragment { kind, data, fin };
+                peer.send(Frame::Fragment(fragment)).await.unwrap();
+            }
+            let received = rx.recv().await.unwrap();
+            assert_eq!(
+                Some(&PacketData::Binary(vec![1, 2, 3, 4, 5])),
+                received.payload.packets()[0].data()
+            );
+            // the fragments of a message over the maximum size are not buffered
+            for kind in [FragmentKind::Binary, FragmentKind::Continuation] {
+                let fragment = Fragment {
+                    kind,
+                    data: vec![1; 4],
+                    fin: false,
+                };
+                peer.send(Frame::Fragment(fragment)).await.unwrap();
+            }
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(matches!(res, Err(EngineError::MessageTooLarge(8))));
+    }
+
+    #[test]
+    fn responder_payload_client_packets() {
+        let sid = Sid::generate();
+        let payload = Payload::try_from("4hello\x1ebAQID\x1e3").unwrap();
+        let checked = ResponderPayload::try_new(sid.clone(), payload.clone()).unwrap();
+        assert_eq!(payload, checked.payload);
+        assert_eq!(sid, checked.sid);
+
+        for (payload, err) in [
+            ("4hello\x1e6", TransportParsingError::InvalidNoopPacket),
+            ("5", TransportParsingError::InvalidUpgradePacket),
+            ("0{}", TransportParsingError::InvalidOpenPacket),
+            ("2probe", TransportParsingError::InvalidPingPacket),
+            ("3probe", TransportParsingError::InvalidPongPacket),
+        ] {
+            let payload = Payload::try_from(payload).unwrap();
+            assert_eq!(
+                Some(err),
+                ResponderPayload::try_new(sid.clone(), payload).err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_rejects_server_only_packets() {
+        let engine = upgraded_websocket_engine();
+        let (socket, sent) = MockWebSocket::new([Frame::Text(Packet::noop().to_string())]);
+        assert!(matches!(
+            engine.run(socket).await,
+            Err(EngineError::TransportParsingErr(
+                TransportParsingError::InvalidNoopPacket
+            ))
+        ));
+        sent.assert_texts(&[]);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_on_mock_socket() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.send(Packet::message_text("queued")).await.unwrap();
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (socket, sent) = MockWebSocket::new([
+            Frame::Text(Packet::ping_probe().to_string()),
+            Frame::Text(Packet::upgrade().to_string()),
+        ]);
+        engine.run(socket).await.unwrap();
+        // the packet queued on polling is flushed, but not the Noop ending the held poll
+        sent.assert_texts(&["3probe", "4queued"]);
+        assert!(session.is_upgraded());
+    }
+
+    #[tokio::test]
+    async fn websocket_permessage_deflate() {
+        let queued = format!("4{}", "queued ".repeat(10));
+        let hello = "hello ".repeat(10);
+        let deflate = Deflate::new(16);
+        for negotiated in [true, false] {
+            let sessions = SessionStore::new();
+            let session = sessions.insert(Sid::generate());
+            session
+                .send(Packet::try_from(queued.as_str()).unwrap())
+                .await
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(1);
+            let engine = EngineBuilder::new()
+                .compression_threshold(16)
+                .build_with_sid(
+                    TransportType::Websocket(WebsocketTransport::default()),
+                    ChannelResponder::new(tx),
+                    session.sid().clone(),
+                    &sessions,
+                )
+                .unwrap();
+            let compressed_hello = Frame::Compressed(Compressed {
+                binary: false,
+                data: deflate.compress(format!("4{}", hello).as_bytes()).unwrap(),
+            });
+            let (socket, sent) = MockWebSocket::new([
+                Frame::Text(Packet::ping_probe().to_string()),
+                Frame::Text(Packet::upgrade().to_string()),
+                compressed_hello,
+            ]);
+            if !negotiated {
+                // a compressed message is only accepted once the extension is negotiated
+                let res = engine.run(socket).await;
+                assert!(matches!(res, Err(EngineError::UnexpectedCompression)));
+                sent.assert_texts(&["3probe", queued.as_str()]);
+                continue;
+            }
+            engine.run(socket.permessage_deflate()).await.unwrap();
+            // the probe is under the threshold, while the queued message is compressed
+            let frames = sent.frames();
+            assert_eq!(Frame::Text("3probe".to_string()), frames[0]);
+            match &frames[1] {
+                Frame::Compressed(compressed) => {
+                    assert!(!compressed.binary);
+                    assert!(compressed.data.len() < queued.len());
+                    let inflated = deflate.inflate(&compressed.data, usize::MAX).unwrap();
+                    assert_eq!(queued.as_bytes(), &inflated[..]);
+                }
+                frame => panic!("expected a compressed frame, got {:?}", frame),
+            }
+            // the compressed message of the client is inflated before being parsed
+            let received = rx.recv().await.unwrap();
+            assert_eq!(
+                Payload::single(Packet::message_text(hello.clone())),
+                received.payload
+            );
+        }
+    }
+
+    #[test]
+    fn negotiate_compression() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::SEC_WEBSOCKET_EXTENSIONS,
+            "permessage-deflate; client_max_window_bits"
+                .parse()
+                .unwrap(),
+        );
+        let mut engine = upgraded_websocket_engine();
+        assert_eq!(None, engine.negotiate_compression(&headers));
+        engine.set_compression_threshold(Some(1024));
+        assert_eq!(
+            Some("permessage-deflate; client_no_context_takeover"),
+            engine.negotiate_compression(&headers)
+        );
+        assert_eq!(None, engine.negotiate_compression(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn websocket_lenient_multi_packet_frames() {
+        for lenient in [true, false] {
+            let sessions = SessionStore::new();
+            let session = sessions.insert(Sid::generate());
+            session.mark_upgraded();
+            let (tx, mut rx) = mpsc::channel(2);
+            let engine = Engine::with_sid(
+                TransportType::Websocket(WebsocketTransport { lenient }),
+                ChannelResponder::new(tx),
+                session.sid().clone(),
+                &sessions,
+            )
+            .unwrap();
+            let (io, mut peer) = ChannelIo::pair();
+            let client = async {
+                peer.send(Frame::Text("4one\x1e3\x1e4two".to_string()))
+                    .await
+                    .unwrap();
+                if lenient {
+                    // each message is dispatched on its own, and the pong is left out
+                    for text in ["one", "two"] {
+                        let received = rx.recv().await.unwrap();
+                        assert_eq!(&[Packet::message_text(text)], received.payload.packets());
+                    }
+                    engine.shutdown();
+                }
+                while next_frame(&mut peer).await.is_some() {}
+            };
+            let (res, _) = tokio::join!(engine.run(io), client);
+            if lenient {
+                assert!(res.is_ok());
+            } else {
+                assert!(matches!(
+                    res,
+                    Err(EngineError::TransportParsingErr(
+                        TransportParsingError::InvalidPayloadForWebsocket(3)
+                    ))
+                ));
+                assert!(rx.try_recv().is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_sends_queued_packets() {
+        let engine = upgraded_websocket_engine();
+        let (io, mut peer) = ChannelIo::pair();
+        let session = engine.session().unwrap().clone();
+        let client = async move {
+            session.send(Packet::message_text("hello")).await.unwrap();
+            session
+                .send(Packet::message_binary(vec![1, 2, 3]))
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(Frame::Text("4hello".to_string())),
+                next_frame(&mut peer).await
+            );
+            assert_eq!(
+                Some(Frame::Binary(vec![1, 2, 3])),
+                next_frame(&mut peer).await
+            );
+            peer.send(Frame::Close(None)).await.unwrap();
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn websocket_shutdown_sends_close() {
+        let engine = upgraded_websocket_engine();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async {
+            peer.send(Frame::Text("4hello".to_string())).await.unwrap();
+            tokio::task::yield_now().await;
+            engine.shutdown();
+            assert_eq!(
+                Some(Frame::Text(Packet::close().to_string())),
+                next_frame(&mut peer).await
+            );
+            let close = CloseFrame {
+                code: 1001,
+                reason: "server shutdown".to_string(),
+            };
+            assert_eq!(Some(Frame::Close(Some(close))), next_frame(&mut peer).await);
+            // the socket is closed after the Close frame
+            assert_eq!(None, next_frame(&mut peer).await);
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+        assert!(engine.sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn websocket_close_with_reason() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let responder = Arc::new(DisconnectRecorder::default());
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            responder.clone(),
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async {
+            tokio::task::yield_now().await;
+            engine.close(DisconnectReason::PolicyViolation);
+            assert_eq!(
+                Some(Frame::Text(Packet::close().to_string())),
+                next_frame(&mut peer).await
+            );
+            let close = CloseFrame {
+                code: 1008,
+                reason: "policy violation".to_string(),
+            };
+            assert_eq!(Some(Frame::Close(Some(close))), next_frame(&mut peer).await);
+            assert_eq!(None, next_frame(&mut peer).await);
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+        assert!(sessions.is_empty());
+        assert_eq!(
+            vec![(session.sid().clone(), DisconnectReason::PolicyViolation)],
+            *responder.reasons.lock().unwrap()
+        );
+    }
+
+    /// Records the reasons passed to `on_disconnect`
+    #[derive(Default)]
+    struct DisconnectRecorder {
+        reasons: std::sync::Mutex<Vec<(Sid, DisconnectReason)>>,
+    }
+
+    #[async_trait]
+    impl Responder for DisconnectRecorder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![]
+        }
+
+        async fn on_disconnect(&self, sid: &Sid, reason: DisconnectReason) {
+            self.reasons.lock().unwrap().push((sid.clone(), reason));
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_close_frame_disconnect_reason() {
+        let sessions = SessionStore::new();
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let sid = session.sid().clone();
+        let responder = Arc::new(DisconnectRecorder::default());
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            responder.clone(),
+            sid.clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        peer.send(Frame::Close(None)).await.unwrap();
+        assert!(engine.run(io).await.is_ok());
+        assert_eq!(
+            vec![(sid, DisconnectReason::ClientClose)],
+            *responder.reasons.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_disconnect_reasons() {
+        let responder = Arc::new(DisconnectRecorder::default());
+        let sessions = SessionStore::new();
+        let engine_for = |session: &Arc<Session>| {
+            session.mark_upgraded();
+            Engine::with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                responder.clone(),
+                session.sid().clone(),
+                &sessions,
+            )
+            .unwrap()
+        };
+
+        let engine = engine_for(&sessions.insert(Sid::generate()));
+        let (io, peer) = ChannelIo::pair();
+        drop(peer);
+        assert!(engine.run(io).await.is_ok());
+
+        let engine = engine_for(&sessions.insert(Sid::generate()));
+        let (io, _peer) = ChannelIo::pair();
+        assert!(engine.run(io).await.is_err());
+
+        let engine = engine_for(&sessions.insert(Sid::generate()));
+        let (io, mut peer) = ChannelIo::pair();
+        peer.send(Frame::Text("9".to_string())).await.unwrap();
+        assert!(engine.run(io).await.is_err());
+
+        let engine = engine_for(&sessions.insert(Sid::generate()));
+        let (io, _peer) = ChannelIo::pair();
+        engine.shutdown();
+        assert!(engine.run(io).await.is_ok());
+
+        let reasons: Vec<_> = responder
+            .reasons
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, reason)| *reason)
+            .collect();
+        assert_eq!(
+            vec![
+                DisconnectReason::TransportClose,
+                DisconnectReason::PingTimeout,
+                DisconnectReason::TransportError,
+                DisconnectReason::ServerShutdown,
+            ],
+            reasons
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_metrics() {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let sessions = SessionStore::new().with_metrics(metrics.clone());
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        assert_eq!(1, metrics.active_sessions());
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            sid,
+            &sessions,
+        )
+        .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        for msg in ["2probe", "5", "4hello", "4world"] {
+            peer.send(Frame::Text(msg.to_string())).await.unwrap();
+        }
+        peer.send(Frame::Close(None)).await.unwrap();
+        assert!(engine.run(io).await.is_ok());
+
+        assert_eq!(2, metrics.packets_received(&PacketType::Message));
+        assert_eq!(1, metrics.packets_received(&PacketType::Ping));
+        assert_eq!(1, metrics.packets_received(&PacketType::Upgrade));
+        assert_eq!(19, metrics.total_bytes_received());
+        assert_eq!(1, metrics.packets_sent(&PacketType::Pong));
+        assert_eq!(6, metrics.total_bytes_sent());
+        assert_eq!(1, metrics.upgrades());
+        assert_eq!(0, metrics.failed_upgrades());
+        assert_eq!(0, metrics.active_sessions());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_ping_timeout_metrics() {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let sessions = SessionStore::new().with_metrics(metrics.clone());
+        let session = sessions.insert(Sid::generate());
+        session.mark_upgraded();
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            session.sid().clone(),
+            &sessions,
+        )
+        .unwrap();
+        let (io, _peer) = ChannelIo::pair();
+        assert!(engine.run(io).await.is_err());
+        assert_eq!(1, metrics.ping_timeouts());
+        assert_eq!(1, metrics.packets_sent(&PacketType::Ping));
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_not_allowed() {
+        let sessions = SessionStore::new();
+        let polling = EngineBuilder::new()
+            .upgrades(vec![])
+            .build(
+                TransportType::Polling(PollingTransport),
+                NoopResponder,
+                &sessions,
+            )
+            .unwrap();
+        let handshake = polling.open_packet().unwrap().to_string();
+        let handshake: serde_json::Value = serde_json::from_str(&handshake[1..]).unwrap();
+        assert_eq!(serde_json::json!([]), handshake["upgrades"]);
+
+        let sid = polling.session().unwrap().sid().clone();
+        let engine = EngineBuilder::new()
+            .upgrades(vec![])
+            .build_with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                sid.clone(),
+                &sessions,
+            )
+            .unwrap();
+        let (io, mut peer) = ChannelIo::pair();
+        peer.send(Frame::Text("2probe".to_string())).await.unwrap();
+        let res = engine.run(io).await;
+        assert!(matches!(res, Err(EngineError::UpgradeNotAllowed)));
+        // the probe is not answered, and the session stays on polling
+        assert_eq!(None, next_frame(&mut peer).await);
+        assert!(!sessions.get(&sid).unwrap().is_upgraded());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tracing_test::traced_test]
+    #[test]
+    fn handshake_span_carries_sid() {
+        let engine = Engine::new(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            &SessionStore::new(),
+        )
+        .unwrap();
+        let sid = engine.sid().unwrap();
+        assert!(logs_contain(&format!("handshake{{sid=\"{}\"}}", sid)));
+    }
+
+    #[test]
+    fn engine_builder() {
+        let engine = EngineBuilder::new()
+            .build(
+                TransportType::Polling(PollingTransport),
+                NoopResponder,
+                &SessionStore::new(),
+            )
+            .unwrap();
+        let config = engine.handshake_config();
+        assert_eq!(Duration::from_millis(25000), config.ping_interval);
+        assert_eq!(Duration::from_millis(20000), config.ping_timeout);
+        assert_eq!(None, engine.compression());
+
+        let sessions = SessionStore::new();
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        let engine = EngineBuilder::new()
+            .ping_interval(Duration::from_secs(1))
+            .ping_timeout(Duration::from_secs(2))
+            .max_payload(10)
+            .upgrades(vec![])
+            .compression(true)
+            .build_with_sid(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                sid,
+                &sessions,
+            )
+            .unwrap();
+        let config = engine.handshake_config();
+        assert_eq!(Duration::from_secs(1), config.ping_interval);
+        assert_eq!(Duration::from_secs(2), config.ping_timeout);
+        assert_eq!(10, config.max_payload);
+        assert!(config.upgrades.is_empty());
+        assert_eq!(
+            Some(&Deflate::new(DEFAULT_COMPRESSION_THRESHOLD)),
+            engine.compression()
+        );
+    }
+
+    /// Generates the sids "sid-0", "sid-1", ...
+    #[derive(Debug, Default)]
+    struct CountingSidGenerator {
+        next: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SidGenerator for CountingSidGenerator {
+        fn generate(&self) -> Sid {
+            let n = self.next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Sid::new(format!("sid-{}", n)).unwrap()
+        }
+    }
+
+    #[test]
+    fn custom_sid_generator() {
+        let sessions = SessionStore::new();
+        let builder = EngineBuilder::new().sid_generator(CountingSidGenerator::default());
+        for i in 0..3 {
+            let engine = builder
+                .clone()
+                .build(
+                    TransportType::Polling(PollingTransport),
+                    NoopResponder,
+                    &sessions,
+                )
+                .unwrap();
+            let sid = format!("sid-{}", i);
+            assert_eq!(Some(sid.as_str()), engine.sid());
+            let handshake = engine.open_packet().unwrap().to_string();
+            let handshake: serde_json::Value = serde_json::from_str(&handshake[1..]).unwrap();
+            assert_eq!(sid, handshake["sid"]);
+        }
+        assert_eq!(3, sessions.len());
+    }
+
+    /// Bypasses `Sid::new`, as only code of the crate can
+    #[derive(Debug)]
+    struct BlankSidGenerator;
+
+    impl SidGenerator for BlankSidGenerator {
+        fn generate(&self) -> Sid {
+            Sid(String::new())
+        }
+    }
+
+    #[test]
+    fn blank_generated_sid_rejected() {
+        let sessions = SessionStore::new();
+        let res = EngineBuilder::new().sid_generator(BlankSidGenerator).build(
+            TransportType::Polling(PollingTransport),
+            NoopResponder,
+            &sessions,
+        );
+        assert!(matches!(res, Err(EngineError::BlankSID)));
+        assert!(sessions.is_empty());
+
+        let state = crate::polling::PollingState::with_config(
+            NoopResponder,
+            crate::polling::PollingConfig {
+                sid_generator: Arc::new(BlankSidGenerator),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(state.open_session(), Err(EngineError::BlankSID)));
+        assert!(state.sessions().is_empty());
+    }
+
+    #[test]
+    fn build_opens_session_within_store_limits() {
+        let sessions = SessionStore::new().with_max_connections(1);
+        let build = || {
+            EngineBuilder::new()
+                .sid_generator(CountingSidGenerator::default())
+                .build(
+                    TransportType::Polling(PollingTransport),
+                    NoopResponder,
+                    &sessions,
+                )
+        };
+        let first = build().unwrap();
+        let session = first.session().unwrap().clone();
+        // the sid of an existing session is refused rather than replacing it
+        assert!(matches!(build(), Err(EngineError::SidInUse)));
+        assert!(Arc::ptr_eq(&session, &sessions.get(session.sid()).unwrap()));
+        assert!(matches!(
+            Engine::new(
+                TransportType::Polling(PollingTransport),
+                NoopResponder,
+                &sessions
+            ),
+            Err(EngineError::TooManyConnections)
+        ));
+        // a websocket handshake opens a session, so it is limited too
+        assert!(matches!(
+            Engine::new(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                &sessions
+            ),
+            Err(EngineError::TooManyConnections)
+        ));
+        assert_eq!(1, sessions.len());
+    }
+
+    #[test]
+    fn compression_disabled_by_default() {
+        let mut engine = upgraded_websocket_engine();
+        assert_eq!(None, engine.compression());
+        engine.set_compression_threshold(Some(1024));
+        assert_eq!(Some(&Deflate::new(1024)), engine.compression());
+    }
+
+    #[test]
+    fn sid_as_map_key() {
+        let sid = Sid::generate();
+        let mut map = HashMap::new();
+        map.insert(sid.clone(), 1);
+        assert_eq!(Some(&1), map.get(&sid.clone()));
+        assert_eq!(sid.as_str(), sid.to_string());
+    }
+
+    #[test]
+    fn blank_sid() {
+        assert!(matches!(
+            Sid::new("".to_string()),
+            Err(EngineError::BlankSID)
+        ));
+    }
+
+    #[tokio::test]
+    async fn websocket_engine_opens_session() {
+        let sessions = SessionStore::new().with_max_connections(1);
+        let engine = Engine::new(
+            TransportType::Websocket(WebsocketTransport::default()),
+            NoopResponder,
+            &sessions,
+        )
+        .unwrap();
+        let sid = Sid::new(engine.sid().unwrap().to_string()).unwrap();
+        assert_eq!(TransportKind::Websocket, engine.transport_kind());
+        assert_eq!(ConnectionState::Open, engine.state());
+        // the websocket handshake counts against the max connections, as the polling one does
+        assert!(matches!(
+            Engine::new(
+                TransportType::Websocket(WebsocketTransport::default()),
+                NoopResponder,
+                &sessions,
+            ),
+            Err(EngineError::TooManyConnections)
+        ));
+
+        // the Open packet is the first packet sent over the socket
+        let open = engine.open_packet().unwrap().to_string();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async move {
+            assert_eq!(Some(Frame::Text(open)), next_frame(&mut peer).await);
+            peer.send(Frame::Close(None)).await.unwrap();
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+        assert!(matches!(
+            sessions.get(&sid),
+            Err(EngineError::UnknownSession)
+        ));
+    }
 }
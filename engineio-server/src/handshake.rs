@@ -0,0 +1,265 @@
+use crate::{EngineError, Sid};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use eio_parser::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The `HandshakeConfig` struct holds the values the server advertises to the client
+/// in the Open packet when a connection is established
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    pub max_payload: usize,
+    /// The transports the client may upgrade to, an empty list keeps the clients on polling
+    pub upgrades: Vec<String>,
+    /// The fields of a json object added to the handshake, e.g. a server region read by custom
+    /// clients. They never replace the standard fields, e.g. `sid`.
+    pub extra: Option<Value>,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            ping_interval: Duration::from_millis(25000),
+            ping_timeout: Duration::from_millis(20000),
+            max_payload: 1_000_000,
+            upgrades: vec!["websocket".to_string()],
+            extra: None,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// Whether the clients may upgrade to the given transport, e.g. "websocket"
+    pub fn allows_upgrade(&self, transport: &str) -> bool {
+        self.upgrades.iter().any(|upgrade| upgrade == transport)
+    }
+
+    /// Builds the Open packet for the given sid, with the handshake serialized as json
+    pub fn open_packet(&self, sid: &Sid) -> Packet {
+        let mut handshake = json!({
+            "sid": sid.as_str(),
+            "upgrades": self.upgrades,
+            "pingInterval": self.ping_interval.as_millis() as u64,
+            "pingTimeout": self.ping_timeout.as_millis() as u64,
+            "maxPayload": self.max_payload,
+        });
+        if let (Some(Value::Object(extra)), Value::Object(fields)) = (&self.extra, &mut handshake) {
+            for (name, value) in extra {
+                fields.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        Packet::open(handshake.to_string())
+    }
+}
+
+/// The request of a client opening a session, or upgrading it to the websocket,
+/// as seen by `Responder::authenticate`
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeRequest {
+    query: HashMap<String, String>,
+    headers: HeaderMap,
+}
+
+impl HandshakeRequest {
+    pub fn new(query: HashMap<String, String>, headers: HeaderMap) -> HandshakeRequest {
+        HandshakeRequest { query, headers }
+    }
+
+    /// The value of a query parameter, e.g. a token passed as `?token=...`
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    /// The value of a header, if it is present and visible ASCII
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The protocol version of the `EIO` query parameter, if it is one of the `supported` versions.
+    /// As with the reference server, a client omitting it is taken to speak V3, which predates it.
+    pub fn protocol_version(
+        &self,
+        supported: &[ProtocolVersion],
+    ) -> Result<ProtocolVersion, EngineError> {
+        let version = match self.query("EIO") {
+            Some("4") => ProtocolVersion::V4,
+            Some("3") | None => ProtocolVersion::V3,
+            Some(_) => return Err(EngineError::UnsupportedProtocolVersion),
+        };
+        if supported.contains(&version) {
+            Ok(version)
+        } else {
+            Err(EngineError::UnsupportedProtocolVersion)
+        }
+    }
+
+    /// The transport of the `transport` query parameter, `None` if it is missing
+    pub fn transport(&self) -> Result<Option<TransportKind>, EngineError> {
+        self.query("transport")
+            .map(|name| TransportKind::from_name(name).ok_or(EngineError::UnknownTransport))
+            .transpose()
+    }
+
+    /// Validates the engine.io query parameters of the handshake, returning its protocol version
+    pub fn validate(&self, supported: &[ProtocolVersion]) -> Result<ProtocolVersion, EngineError> {
+        self.transport()?;
+        self.protocol_version(supported)
+    }
+}
+
+/// Why `Responder::authenticate` refused a connection. The handshake fails with the status
+/// of the rejection, and the engine.io "Forbidden" error json.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum AuthRejection {
+    /// The client did not authenticate, e.g. its token is missing
+    #[error("Unauthorized")]
+    Unauthorized,
+    /// The client authenticated, but is not allowed to connect
+    #[error("Forbidden")]
+    Forbidden,
+}
+
+impl AuthRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthRejection::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthRejection::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        // 4 is the engine.io error code of a forbidden handshake
+        let body = json!({
+            "code": 4,
+            "message": self.to_string(),
+        });
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_packet_handshake() {
+        let sid = Sid::new("abc".to_string()).unwrap();
+        let packet = HandshakeConfig::default().open_packet(&sid);
+        assert_eq!(&PacketType::Open, packet.packet_type());
+        let encoded = packet.to_string();
+        assert!(encoded.starts_with('0'));
+        let handshake: Value = serde_json::from_str(&encoded[1..]).unwrap();
+        assert_eq!(
+            json!({
+                "sid": "abc",
+                "upgrades": ["websocket"],
+                "pingInterval": 25000,
+                "pingTimeout": 20000,
+                "maxPayload": 1000000,
+            }),
+            handshake
+        );
+    }
+
+    #[test]
+    fn handshake_query_validation() {
+        let request = |query: &[(&str, &str)]| {
+            let query = query
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            HandshakeRequest::new(query, HeaderMap::new())
+        };
+        let v4 = [ProtocolVersion::V4];
+        let v3_and_v4 = [ProtocolVersion::V3, ProtocolVersion::V4];
+
+        let eio4 = request(&[("EIO", "4"), ("transport", "polling")]);
+        assert_eq!(ProtocolVersion::V4, eio4.validate(&v4).unwrap());
+        assert_eq!(Some(TransportKind::Polling), eio4.transport().unwrap());
+
+        // V3 is rejected unless supported, and assumed when the version is missing
+        for eio3 in [request(&[("EIO", "3")]), request(&[])] {
+            assert!(matches!(
+                eio3.validate(&v4),
+                Err(EngineError::UnsupportedProtocolVersion)
+            ));
+            assert_eq!(ProtocolVersion::V3, eio3.validate(&v3_and_v4).unwrap());
+        }
+        assert!(matches!(
+            request(&[("EIO", "5")]).validate(&v3_and_v4),
+            Err(EngineError::UnsupportedProtocolVersion)
+        ));
+        assert_eq!(5, EngineError::UnsupportedProtocolVersion.code());
+
+        let bogus = request(&[("EIO", "4"), ("transport", "flashsocket")]);
+        assert!(matches!(
+            bogus.validate(&v4),
+            Err(EngineError::UnknownTransport)
+        ));
+        assert_eq!(None, request(&[("EIO", "4")]).transport().unwrap());
+    }
+
+    #[test]
+    fn handshake_extra_fields() {
+        let sid = Sid::new("abc".to_string()).unwrap();
+        let config = HandshakeConfig {
+            extra: Some(json!({
+                "region": "eu-west",
+                "features": ["compression"],
+                "sid": "clobbered",
+                "pingInterval": 1,
+            })),
+            ..HandshakeConfig::default()
+        };
+        let encoded = config.open_packet(&sid).to_string();
+        let handshake: Value = serde_json::from_str(&encoded[1..]).unwrap();
+        assert_eq!(
+            json!({
+                "sid": "abc",
+                "upgrades": ["websocket"],
+                "pingInterval": 25000,
+                "pingTimeout": 20000,
+                "maxPayload": 1000000,
+                "region": "eu-west",
+                "features": ["compression"],
+            }),
+            handshake
+        );
+
+        // extra fields that are not an object are ignored
+        let config = HandshakeConfig {
+            extra: Some(json!("region")),
+            ..HandshakeConfig::default()
+        };
+        assert_eq!(
+            HandshakeConfig::default().open_packet(&sid),
+            config.open_packet(&sid)
+        );
+    }
+
+    #[test]
+    fn empty_upgrades() {
+        let sid = Sid::new("abc".to_string()).unwrap();
+        let config = HandshakeConfig {
+            upgrades: vec![],
+            ..HandshakeConfig::default()
+        };
+        assert!(!config.allows_upgrade("websocket"));
+        assert!(HandshakeConfig::default().allows_upgrade("websocket"));
+        let encoded = config.open_packet(&sid).to_string();
+        let handshake: Value = serde_json::from_str(&encoded[1..]).unwrap();
+        assert_eq!(json!([]), handshake["upgrades"]);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::engine::EngineError;
+use crate::transport::TransportParsingError;
+use async_trait::async_trait;
+use axum::extract::ws::{self, Message};
+use eio_parser::PacketParsingError;
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+/// A websocket frame, independent of the framework serving the websocket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A part of a message split over several frames, e.g. by an intermediary,
+    /// which `ReassemblingIo` joins back into a text or binary frame. Only custom adapters
+    /// exposing the raw frames return fragments: the websocket of axum joins them itself,
+    /// so `AxumIo` never does.
+    Fragment(Fragment),
+    /// A message compressed by `permessage-deflate`, i.e. sent with the RSV1 bit set,
+    /// which is only exchanged with sockets reporting `TransportIo::permessage_deflate`
+    Compressed(Compressed),
+    /// The peer is closing the connection, optionally telling why
+    Close(Option<CloseFrame>),
+}
+
+/// The opcode of a fragment. The first fragment of a message tells whether it is text or binary,
+/// while the following ones are continuations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentKind {
+    Text,
+    Binary,
+    Continuation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    pub kind: FragmentKind,
+    pub data: Vec<u8>,
+    /// Whether this is the last fragment of the message
+    pub fin: bool,
+}
+
+/// The raw deflate data of a compressed message, which is not fragmented
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compressed {
+    /// Whether the message is binary once inflated, rather than text
+    pub binary: bool,
+    pub data: Vec<u8>,
+}
+
+/// The status code and reason of a websocket Close frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Frame {
+    /// The length of the data of the frame in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(bytes) => bytes.len(),
+            Frame::Fragment(fragment) => fragment.data.len(),
+            Frame::Compressed(compressed) => compressed.data.len(),
+            Frame::Close(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        match self {
+            Frame::Text(text) => text.into_bytes(),
+            Frame::Binary(bytes) => bytes,
+            Frame::Fragment(fragment) => fragment.data,
+            Frame::Compressed(compressed) => compressed.data,
+            Frame::Close(_) => Vec::new(),
+        }
+    }
+}
+
+/// The trait `TransportIo` is the websocket as seen by the engine, so that `Engine::run`
+/// does not depend on a framework. Control frames, e.g. websocket pings, are handled by the
+/// implementation and never returned by `recv`.
+#[async_trait]
+pub trait TransportIo: Send {
+    /// Reads the next frame, returning `None` once the connection is closed
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>>;
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError>;
+
+    /// Closes the connection, ignoring any error since the connection is given up anyway
+    async fn close(&mut self);
+
+    /// Whether `permessage-deflate` was negotiated for the connection, in which case compressed
+    /// messages are exchanged as `Frame::Compressed`. Sockets that cannot set the RSV1 bit of
+    /// their frames, such as the websocket of axum, never negotiate it.
+    fn permessage_deflate(&self) -> bool {
+        false
+    }
+}
+
+/// Adapts an `axum::extract::ws::WebSocket`, or any stream and sink of axum websocket messages.
+/// Only text and binary messages carry engine.io packets. The websocket Ping and Pong control
+/// frames of RFC 6455 are unrelated to the engine.io heartbeat and are skipped: the `WebSocket`
+/// of axum already answers each Ping with a Pong carrying the same data.
+#[derive(Debug)]
+pub struct AxumIo<S>(S);
+
+impl<S> AxumIo<S> {
+    pub fn new(socket: S) -> AxumIo<S> {
+        AxumIo(socket)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+#[async_trait]
+impl<S> TransportIo for AxumIo<S>
+where
+    S: Stream<Item = Result<Message, axum::Error>>
+        + Sink<Message, Error = axum::Error>
+        + Unpin
+        + Send,
+{
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>> {
+        loop {
+            let frame = match self.0.next().await? {
+                Ok(Message::Text(text)) => Frame::Text(text),
+                Ok(Message::Binary(bytes)) => Frame::Binary(bytes),
+                Ok(Message::Close(close)) => Frame::Close(close.map(|close| CloseFrame {
+                    code: close.code,
+                    reason: close.reason.into_owned(),
+                })),
+                // control frames, never parsed into engine.io packets
+                Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                Err(e) => return Some(Err(EngineError::ConnWebsocketErr(e))),
+            };
+            return Some(Ok(frame));
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError> {
+        let msg = match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(bytes) => Message::Binary(bytes),
+            Frame::Close(close) => Message::Close(close.map(|close| ws::CloseFrame {
+                code: close.code,
+                reason: close.reason.into(),
+            })),
+            // axum writes each message as a single frame
+            Frame::Fragment(_) => {
+                return Err(EngineError::ConnWebsocketErr(axum::Error::new(
+                    "sending fragments is not supported",
+                )))
+            }
+            // the RSV1 bit of compressed messages cannot be set through axum
+            Frame::Compressed(_) => {
+                return Err(EngineError::ConnWebsocketErr(axum::Error::new(
+                    "sending compressed messages is not supported",
+                )))
+            }
+        };
+        self.0
+            .send(msg)
+            .await
+            .map_err(EngineError::ConnWebsocketErr)
+    }
+
+    async fn close(&mut self) {
+        let _ = self.0.close().await;
+    }
+}
+
+/// An in-memory connection, where the frames sent on one end are received on the other.
+/// It is useful to drive an engine without a socket, e.g. in tests.
+#[derive(Debug)]
+pub struct ChannelIo {
+    tx: mpsc::UnboundedSender<Frame>,
+    rx: mpsc::UnboundedReceiver<Frame>,
+}
+
+impl ChannelIo {
+    /// Creates both ends of a connection
+    pub fn pair() -> (ChannelIo, ChannelIo) {
+        let (a_tx, a_rx) = mpsc::unbounded();
+        let (b_tx, b_rx) = mpsc::unbounded();
+        (
+            ChannelIo { tx: a_tx, rx: b_rx },
+            ChannelIo { tx: b_tx, rx: a_rx },
+        )
+    }
+}
+
+#[async_trait]
+impl TransportIo for ChannelIo {
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>> {
+        self.rx.next().await.map(Ok)
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError> {
+        self.tx
+            .unbounded_send(frame)
+            .map_err(|e| EngineError::ConnWebsocketErr(axum::Error::new(e.into_send_error())))
+    }
+
+    async fn close(&mut self) {
+        self.tx.close_channel();
+    }
+}
+
+/// Wraps a `TransportIo` to join the fragments of each message before returning it from `recv`.
+/// A message over `max_message_size` bytes is rejected with `EngineError::MessageTooLarge`,
+/// so that a peer cannot make the fragments buffer grow without bound.
+/// It is only needed by custom adapters returning `Frame::Fragment`: over `AxumIo`, messages
+/// are already joined, and their size is limited by the upgrade, see `websocket_router`.
+#[derive(Debug)]
+pub struct ReassemblingIo<T> {
+    io: T,
+    max_message_size: usize,
+    /// Whether the message being reassembled is binary, and its data so far
+    partial: Option<(bool, Vec<u8>)>,
+}
+
+impl<T: TransportIo> ReassemblingIo<T> {
+    pub fn new(io: T, max_message_size: usize) -> ReassemblingIo<T> {
+        ReassemblingIo {
+            io,
+            max_message_size,
+            partial: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Returns the frame once its message is complete, or `None` while more fragments are expected
+    fn reassemble(&mut self, frame: Frame) -> Result<Option<Frame>, EngineError> {
+        if frame.len() > self.max_message_size {
+            return Err(EngineError::MessageTooLarge(frame.len()));
+        }
+        let fragment = match frame {
+            Frame::Fragment(fragment) => fragment,
+            // the fragments of a message may only be interleaved with control frames
+            Frame::Text(_) | Frame::Binary(_) | Frame::Compressed(_) if self.partial.is_some() => {
+                return Err(EngineError::InvalidFragment)
+            }
+            frame => return Ok(Some(frame)),
+        };
+        let (binary, mut data) = match (fragment.kind, self.partial.take()) {
+            (FragmentKind::Continuation, Some(partial)) => partial,
+            (FragmentKind::Continuation, None) | (_, Some(_)) => {
+                return Err(EngineError::InvalidFragment)
+            }
+            (kind, None) => (kind == FragmentKind::Binary, Vec::new()),
+        };
+        let len = data.len() + fragment.data.len();
+        if len > self.max_message_size {
+            return Err(EngineError::MessageTooLarge(len));
+        }
+        data.extend_from_slice(&fragment.data);
+        if !fragment.fin {
+            self.partial = Some((binary, data));
+            return Ok(None);
+        }
+        if binary {
+            return Ok(Some(Frame::Binary(data)));
+        }
+        String::from_utf8(data)
+            .map(|text| Some(Frame::Text(text)))
+            .map_err(|_| TransportParsingError::from(PacketParsingError::NotUtf8).into())
+    }
+}
+
+#[async_trait]
+impl<T: TransportIo> TransportIo for ReassemblingIo<T> {
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>> {
+        loop {
+            // the partial message lives in self, so no fragment is lost if this future is dropped
+            let frame = match self.io.recv().await? {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(frame) = self.reassemble(frame).transpose() {
+                return Some(frame);
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError> {
+        self.io.send(frame).await
+    }
+
+    async fn close(&mut self) {
+        self.io.close().await
+    }
+
+    fn permessage_deflate(&self) -> bool {
+        self.io.permessage_deflate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    #[tokio::test]
+    async fn channel_io_pair() {
+        let (mut a, mut b) = ChannelIo::pair();
+        a.send(Frame::Text("4hello".to_string())).await.unwrap();
+        b.send(Frame::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(
+            Some(Frame::Text("4hello".to_string())),
+            b.recv().await.transpose().unwrap()
+        );
+        assert_eq!(
+            Some(Frame::Binary(vec![1, 2, 3])),
+            a.recv().await.transpose().unwrap()
+        );
+        a.close().await;
+        assert!(b.recv().await.is_none());
+        assert!(a.send(Frame::Close(None)).await.is_err());
+    }
+
+    /// A fake axum websocket, where the test pushes the inbound messages and reads the outbound ones
+    struct MockSocket {
+        inbound: mpsc::UnboundedReceiver<Result<Message, axum::Error>>,
+        outbound: mpsc::UnboundedSender<Message>,
+    }
+
+    impl Stream for MockSocket {
+        type Item = Result<Message, axum::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.inbound).poll_next(cx)
+        }
+    }
+
+    impl Sink<Message> for MockSocket {
+        type Error = axum::Error;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outbound)
+                .poll_ready(cx)
+                .map_err(axum::Error::new)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            Pin::new(&mut self.outbound)
+                .start_send(item)
+                .map_err(axum::Error::new)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outbound)
+                .poll_flush(cx)
+                .map_err(axum::Error::new)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outbound)
+                .poll_close(cx)
+                .map_err(axum::Error::new)
+        }
+    }
+
+    #[tokio::test]
+    async fn axum_io_frames() {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let (outbound, mut outbound_rx) = mpsc::unbounded();
+        let mut io = AxumIo::new(MockSocket { inbound, outbound });
+        for msg in [
+            Message::Ping(vec![1]),
+            Message::Text("4hello".to_string()),
+            Message::Pong(vec![1]),
+            Message::Binary(vec![1, 2, 3]),
+            Message::Close(Some(ws::CloseFrame {
+                code: 1000,
+                reason: "bye".into(),
+            })),
+        ] {
+            inbound_tx.unbounded_send(Ok(msg)).unwrap();
+        }
+        // the control frames are skipped
+        for frame in [
+            Frame::Text("4hello".to_string()),
+            Frame::Binary(vec![1, 2, 3]),
+            Frame::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".to_string(),
+            })),
+        ] {
+            assert_eq!(Some(frame), io.recv().await.transpose().unwrap());
+        }
+
+        io.send(Frame::Text("4hi".to_string())).await.unwrap();
+        io.send(Frame::Binary(vec![4])).await.unwrap();
+        io.send(Frame::Close(None)).await.unwrap();
+        io.close().await;
+        assert_eq!(
+            Some(Message::Text("4hi".to_string())),
+            outbound_rx.next().await
+        );
+        assert_eq!(Some(Message::Binary(vec![4])), outbound_rx.next().await);
+        assert_eq!(Some(Message::Close(None)), outbound_rx.next().await);
+        assert_eq!(None, outbound_rx.next().await);
+
+        drop(inbound_tx);
+        assert!(io.recv().await.is_none());
+    }
+
+    fn fragment(kind: FragmentKind, data: &[u8], fin: bool) -> Frame {
+        Frame::Fragment(Fragment {
+            kind,
+            data: data.to_vec(),
+            fin,
+        })
+    }
+
+    #[tokio::test]
+    async fn reassembling_io_fragments() {
+        let (io, mut peer) = ChannelIo::pair();
+        let mut io = ReassemblingIo::new(io, 8);
+        for frame in [
+            fragment(FragmentKind::Text, b"4h\xc3", false),
+            // a control frame may come between the fragments
+            Frame::Close(None),
+            fragment(FragmentKind::Continuation, b"\xa9llo", true),
+            Frame::Text("4whole".to_string()),
+        ] {
+            peer.send(frame).await.unwrap();
+        }
+        assert_eq!(
+            Some(Frame::Close(None)),
+            io.recv().await.transpose().unwrap()
+        );
+        assert_eq!(
+            Some(Frame::Text("4héllo".to_string())),
+            io.recv().await.transpose().unwrap()
+        );
+        assert_eq!(
+            Some(Frame::Text("4whole".to_string())),
+            io.recv().await.transpose().unwrap()
+        );
+
+        peer.send(fragment(FragmentKind::Binary, &[1; 5], false))
+            .await
+            .unwrap();
+        peer.send(fragment(FragmentKind::Continuation, &[2; 4], true))
+            .await
+            .unwrap();
+        assert!(matches!(
+            io.recv().await,
+            Some(Err(EngineError::MessageTooLarge(9)))
+        ));
+        peer.send(Frame::Binary(vec![0; 9])).await.unwrap();
+        assert!(matches!(
+            io.recv().await,
+            Some(Err(EngineError::MessageTooLarge(9)))
+        ));
+
+        peer.send(fragment(FragmentKind::Continuation, b"4", true))
+            .await
+            .unwrap();
+        assert!(matches!(
+            io.recv().await,
+            Some(Err(EngineError::InvalidFragment))
+        ));
+        peer.send(fragment(FragmentKind::Text, b"4", false))
+            .await
+            .unwrap();
+        peer.send(Frame::Text("4".to_string())).await.unwrap();
+        assert!(matches!(
+            io.recv().await,
+            Some(Err(EngineError::InvalidFragment))
+        ));
+
+        peer.close().await;
+        assert!(io.recv().await.is_none());
+    }
+}
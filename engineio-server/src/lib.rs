@@ -3,8 +3,28 @@
 //! Server is also in charge of sending ping packets to the client.
 //! Currently, the initial release of the project is only targeting V4 of the engineio protocol.
 
+mod clock;
+mod compression;
+mod cors;
 mod transport;
 mod engine;
+mod handshake;
+mod io;
+mod metrics;
+mod polling;
+mod session;
+#[cfg(test)]
+mod test_support;
+mod websocket;
 
+pub use clock::*;
+pub use compression::*;
+pub use cors::*;
 pub use transport::*;
-pub use engine::*;
\ No newline at end of file
+pub use engine::*;
+pub use handshake::*;
+pub use io::*;
+pub use metrics::*;
+pub use polling::*;
+pub use session::*;
+pub use websocket::*;
\ No newline at end of file
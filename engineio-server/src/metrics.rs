@@ -0,0 +1,130 @@
+use eio_parser::PacketType;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The number of packet types, from Open (0) to Noop (6)
+const PACKET_TYPES: usize = 7;
+
+/// The trait `Metrics` is notified of the traffic of the engine, e.g. to export it to a
+/// monitoring system. Every method does nothing by default, so implementors only
+/// override the events they are interested in.
+pub trait Metrics: fmt::Debug + Send + Sync {
+    /// A packet was received from a client, on either transport
+    fn packet_received(&self, _packet_type: &PacketType) {}
+
+    /// A packet was sent to a client, on either transport
+    fn packet_sent(&self, _packet_type: &PacketType) {}
+
+    /// The bytes of a polling request body or websocket message received from a client
+    fn bytes_received(&self, _bytes: usize) {}
+
+    /// The bytes of a polling response body or websocket message sent to a client
+    fn bytes_sent(&self, _bytes: usize) {}
+
+    /// A session was created in the store
+    fn session_opened(&self) {}
+
+    /// A session was removed from the store
+    fn session_closed(&self) {}
+
+    /// A session completed, or failed, the upgrade from polling to the websocket
+    fn upgrade(&self, _success: bool) {}
+
+    /// A websocket was closed as the client did not respond to a ping in time
+    fn ping_timeout(&self) {}
+}
+
+/// The `InMemoryMetrics` counts the events in atomic counters, which can be read at any time
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    packets_received: [AtomicU64; PACKET_TYPES],
+    packets_sent: [AtomicU64; PACKET_TYPES],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    active_sessions: AtomicUsize,
+    upgrades: AtomicU64,
+    failed_upgrades: AtomicU64,
+    ping_timeouts: AtomicU64,
+}
+
+fn index(packet_type: &PacketType) -> usize {
+    packet_type.as_char() as usize - '0' as usize
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> InMemoryMetrics {
+        InMemoryMetrics::default()
+    }
+
+    pub fn packets_received(&self, packet_type: &PacketType) -> u64 {
+        self.packets_received[index(packet_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn packets_sent(&self, packet_type: &PacketType) -> u64 {
+        self.packets_sent[index(packet_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn active_sessions(&self) -> usize {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn upgrades(&self) -> u64 {
+        self.upgrades.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_upgrades(&self) -> u64 {
+        self.failed_upgrades.load(Ordering::Relaxed)
+    }
+
+    pub fn ping_timeouts(&self) -> u64 {
+        self.ping_timeouts.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn packet_received(&self, packet_type: &PacketType) {
+        self.packets_received[index(packet_type)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn packet_sent(&self, packet_type: &PacketType) {
+        self.packets_sent[index(packet_type)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn session_opened(&self) {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn session_closed(&self) {
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn upgrade(&self, success: bool) {
+        let counter = if success {
+            &self.upgrades
+        } else {
+            &self.failed_upgrades
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn ping_timeout(&self) {
+        self.ping_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
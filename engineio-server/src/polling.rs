@@ -0,0 +1,1151 @@
+use crate::cors::CorsConfig;
+use crate::engine::*;
+use crate::handshake::{HandshakeConfig, HandshakeRequest};
+use crate::session::*;
+use crate::transport::*;
+use axum::body::{Body, HttpBody};
+use axum::extract::{Extension, Query, RawBody};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Headers, IntoResponse, Response};
+use axum::routing::get;
+use axum::{AddExtensionLayer, Json, Router};
+use eio_parser::{Packet, PacketData, PacketParsingError, ProtocolVersion, TransportKind};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+/// The query parameters sent by the client on each polling request
+#[derive(Debug, Deserialize)]
+pub struct PollingQuery {
+    pub sid: Option<String>,
+    /// The index of the JSONP callback, sent by clients that fall back to JSONP polling
+    pub j: Option<String>,
+    /// The transport of the request, i.e. "polling" or "websocket"
+    pub transport: Option<String>,
+}
+
+impl PollingQuery {
+    /// Parses the transport of the request, which may be omitted,
+    /// returning `EngineError::UnknownTransport` for any other value than "polling" or "websocket"
+    pub fn transport_kind(&self) -> Result<Option<TransportKind>, EngineError> {
+        self.transport
+            .as_deref()
+            .map(|name| TransportKind::from_name(name).ok_or(EngineError::UnknownTransport))
+            .transpose()
+    }
+}
+
+/// The `PollingConfig` struct holds the settings of the polling endpoints
+#[derive(Debug, Clone)]
+pub struct PollingConfig {
+    pub cors: CorsConfig,
+    /// The handshake advertised in the Open packet of new sessions
+    pub handshake: HandshakeConfig,
+    /// How long a GET request is held open while no packet is queued,
+    /// after which a Noop packet is returned so that the client polls again
+    pub poll_timeout: Duration,
+    /// Dispatches each packet of a POST to the responder individually, see
+    /// `EngineBuilder::per_packet_dispatch`
+    pub per_packet_dispatch: bool,
+    /// The `maxHttpBufferSize` of engine.io, i.e. the largest body of a POST request in bytes.
+    /// It is independent of the `max_message_size` of the websocket.
+    pub max_http_buffer_size: usize,
+    /// Generates the sids of the sessions opened by the handshake, `RandomSidGenerator` by default
+    pub sid_generator: Arc<dyn SidGenerator>,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            cors: CorsConfig::default(),
+            handshake: HandshakeConfig::default(),
+            poll_timeout: Duration::from_millis(25000),
+            per_packet_dispatch: false,
+            max_http_buffer_size: 1_000_000,
+            sid_generator: Arc::new(RandomSidGenerator),
+        }
+    }
+}
+
+/// The `PollingState` is shared by the polling handlers across requests.
+/// It holds the responder, along with the store of sessions and the polling configuration.
+#[derive(Debug)]
+pub struct PollingState<R: Responder> {
+    responder: Arc<R>,
+    sessions: SessionStore,
+    config: PollingConfig,
+}
+
+impl<R: Responder> PollingState<R> {
+    pub fn new(responder: R) -> Arc<PollingState<R>> {
+        PollingState::with_config(responder, PollingConfig::default())
+    }
+
+    pub fn with_config(responder: R, config: PollingConfig) -> Arc<PollingState<R>> {
+        PollingState::with_sessions(responder, config, SessionStore::new())
+    }
+
+    /// Creates the state with a configured store, e.g. one with metrics or a grace period
+    pub fn with_sessions(
+        responder: R,
+        config: PollingConfig,
+        sessions: SessionStore,
+    ) -> Arc<PollingState<R>> {
+        Arc::new(PollingState {
+            responder: Arc::new(responder),
+            sessions,
+            config,
+        })
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    /// Opens a new session, queueing the Open packet to be returned on the first GET request.
+    /// The sid comes from the `sid_generator` of the config, and the session is inserted with
+    /// `SessionStore::try_insert`, e.g. failing with `EngineError::TooManyConnections`.
+    pub fn open_session(&self) -> Result<Sid, EngineError> {
+        let sid = generate_sid(self.config.sid_generator.as_ref())?;
+        let session = self.sessions.try_insert(sid)?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(sid = session.sid().as_str(), "opened polling session");
+        let handshake = &self.config.handshake;
+        session.send_control(handshake.open_packet(session.sid()));
+        // a client that stops polling is expected to miss a ping, as it would on the websocket
+        self.sessions.watch_liveness(
+            session.sid(),
+            handshake.ping_interval + handshake.ping_timeout,
+        );
+        Ok(session.sid().clone())
+    }
+
+    /// Creates the engine serving a single request of the session
+    fn engine(&self, sid: Option<String>) -> Result<Engine<Arc<R>>, EngineError> {
+        let sid = Sid::new(sid.unwrap_or_default())?;
+        EngineBuilder::new()
+            .handshake(self.config.handshake.clone())
+            .per_packet_dispatch(self.config.per_packet_dispatch)
+            .build_with_sid(
+                TransportType::Polling(PollingTransport),
+                self.responder.clone(),
+                sid,
+                &self.sessions,
+            )
+    }
+}
+
+/// Builds the router serving the polling transport, with the sid passed as a query parameter
+pub fn polling_router<R: Responder + 'static>(state: Arc<PollingState<R>>) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(polling_get::<R>)
+                .post(polling_post::<R>)
+                .options(polling_preflight::<R>),
+        )
+        .layer(AddExtensionLayer::new(state))
+}
+
+/// The preflight OPTIONS request of a cross-origin client
+async fn polling_preflight<R: Responder>(
+    Extension(state): Extension<Arc<PollingState<R>>>,
+    headers: HeaderMap,
+) -> (StatusCode, HeaderMap) {
+    let cors = state
+        .config
+        .cors
+        .preflight_headers(headers.get(header::ORIGIN));
+    (StatusCode::NO_CONTENT, cors)
+}
+
+async fn polling_get<R: Responder>(
+    Extension(state): Extension<Arc<PollingState<R>>>,
+    Query(query): Query<PollingQuery>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> (HeaderMap, Result<Response, ErrorResponse>) {
+    let cors = state.config.cors.headers(headers.get(header::ORIGIN));
+    let request = HandshakeRequest::new(params, headers);
+    (cors, poll(&state, query, request).await)
+}
+
+/// The GET request returns the buffered outbound packets, holding the request open until one
+/// is available or the poll timeout elapses, in which case a Noop packet is returned.
+/// A GET request without a sid is the handshake, which returns the Open packet of a new session,
+/// unless `Responder::authenticate` rejects it. Only V4 clients are accepted by the handshake.
+async fn poll<R: Responder>(
+    state: &PollingState<R>,
+    query: PollingQuery,
+    request: HandshakeRequest,
+) -> Result<Response, ErrorResponse> {
+    query.transport_kind().map_err(error_response)?;
+    // the first request of a client carries no sid, and opens its session once authenticated
+    let sid = match query.sid {
+        Some(sid) => sid,
+        None => {
+            request
+                .validate(&[ProtocolVersion::V4])
+                .map_err(error_response)?;
+            if let Err(rejection) = state.responder.authenticate(&request).await {
+                return Ok(rejection.into_response());
+            }
+            state.open_session().map_err(error_response)?.to_string()
+        }
+    };
+    let engine = state.engine(Some(sid)).map_err(error_response)?;
+    let session = engine
+        .session()
+        .ok_or(error_response(EngineError::UnknownSession))?;
+    // the packets that do not fit in the max payload are left for the next poll
+    let max_payload = engine.handshake_config().max_payload;
+    let mut packets = time::timeout(state.config.poll_timeout, session.recv_up_to(max_payload))
+        .await
+        .unwrap_or_default();
+    let body = engine
+        .complete_poll(session, &mut packets)
+        .map_err(error_response)?;
+    Ok(match query.j {
+        Some(j) => (
+            Headers([(header::CONTENT_TYPE, "text/javascript; charset=UTF-8")]),
+            encode_jsonp(&j, &body),
+        )
+            .into_response(),
+        None => (
+            Headers([(header::CONTENT_TYPE, content_type(&packets))]),
+            body,
+        )
+            .into_response(),
+    })
+}
+
+/// The content type of a polling response, which is `application/octet-stream` when
+/// every packet carries binary data, and `text/plain` otherwise
+fn content_type(packets: &[Packet]) -> &'static str {
+    let binary_only = !packets.is_empty()
+        && packets
+            .iter()
+            .all(|p| matches!(p.data(), Some(PacketData::Binary(_))));
+    if binary_only {
+        "application/octet-stream"
+    } else {
+        "text/plain; charset=UTF-8"
+    }
+}
+
+/// Wraps the payload in the JSONP callback `___eio[<j>]("...")`.
+/// Only the digits of `j` are kept, and the payload is escaped as a JSON string,
+/// along with U+2028 and U+2029 which are not valid inside javascript strings.
+pub fn encode_jsonp(j: &str, payload: &str) -> String {
+    let index: String = j.chars().filter(char::is_ascii_digit).collect();
+    let escaped = serde_json::to_string(payload)
+        .expect("strings always serialize")
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029");
+    format!("___eio[{}]({});", index, escaped)
+}
+
+async fn polling_post<R: Responder>(
+    Extension(state): Extension<Arc<PollingState<R>>>,
+    Query(query): Query<PollingQuery>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> (HeaderMap, Result<&'static str, ErrorResponse>) {
+    let cors = state.config.cors.headers(headers.get(header::ORIGIN));
+    (cors, post_payload(&state, query, &headers, body).await)
+}
+
+/// The POST request carries a payload from the client, which is dispatched to the responder.
+/// The replies of the responder are queued on the session. A body over the
+/// `max_http_buffer_size` is rejected with 413 Payload Too Large, without being buffered.
+async fn post_payload<R: Responder>(
+    state: &PollingState<R>,
+    query: PollingQuery,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<&'static str, ErrorResponse> {
+    query.transport_kind().map_err(error_response)?;
+    let engine = state.engine(query.sid).map_err(error_response)?;
+    let session = engine
+        .session()
+        .ok_or(error_response(EngineError::UnknownSession))?;
+    let body = read_body(headers, body, state.config.max_http_buffer_size)
+        .await
+        .map_err(error_response)?;
+    // the body is validated in place, rather than copied into a string
+    let body = std::str::from_utf8(&body).map_err(|_| {
+        error_response(TransportParsingError::from(PacketParsingError::NotUtf8).into())
+    })?;
+    // the replies are returned by the next GET request
+    engine
+        .handle_polling_post(session.sid(), body)
+        .await
+        .map_err(error_response)?;
+    Ok("ok")
+}
+
+/// Reads the body of a request, failing with `EngineError::PayloadTooLarge` as soon as it is
+/// known to be over `limit` bytes: from its `Content-Length` before reading anything, or once
+/// the chunks read so far are over the limit, whatever the `Content-Length` claimed.
+async fn read_body(
+    headers: &HeaderMap,
+    mut body: Body,
+    limit: usize,
+) -> Result<Vec<u8>, EngineError> {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if let Some(len) = content_length.filter(|len| *len > limit) {
+        return Err(EngineError::PayloadTooLarge(len));
+    }
+    let mut buf = Vec::with_capacity(content_length.unwrap_or(0));
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| EngineError::BodyRead(axum::Error::new(e)))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(EngineError::PayloadTooLarge(buf.len() + chunk.len()));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// A failed polling request returns the engine.io error json, e.g.
+/// `{"code":1,"message":"Session ID unknown"}`, with the status of `EngineError::status`
+type ErrorResponse = (StatusCode, Json<Value>);
+
+/// A failed request is answered with the status and the engine.io error code of the error
+impl IntoResponse for EngineError {
+    fn into_response(self) -> Response {
+        error_response(self).into_response()
+    }
+}
+
+fn error_response(err: EngineError) -> ErrorResponse {
+    // the message of the inner transport error tells the client what was wrong with the
+    // payload, rather than the generic message of `EngineError::TransportParsingErr`
+    let message = match &err {
+        EngineError::TransportParsingErr(transport_err) => transport_err.to_string(),
+        _ => err.to_string(),
+    };
+    let body = json!({
+        "code": err.code(),
+        "message": message,
+    });
+    (err.status(), Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::AuthRejection;
+    use crate::io::{ChannelIo, Frame, TransportIo};
+    use crate::metrics::InMemoryMetrics;
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use eio_parser::PacketType;
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    async fn request(
+        router: &Router,
+        method: Method,
+        uri: String,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn polling_get_and_post() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?EIO=4&transport=polling&sid={}", sid.as_str());
+
+        let (status, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.starts_with('0'));
+        assert!(body.contains(sid.as_str()));
+
+        let (status, body) = request(&router, Method::POST, uri.clone(), "4hello\x1e4world").await;
+        assert_eq!((StatusCode::OK, "ok".to_string()), (status, body));
+        let received = rx.recv().await.unwrap();
+        assert_eq!(sid.as_str(), received.sid.as_str());
+        assert_eq!(2, received.payload.len());
+
+        let session = state.sessions().get(&sid).unwrap();
+        session.send(Packet::message_text("hi")).await.unwrap();
+        session
+            .send(Packet::message_binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!((StatusCode::OK, "4hi\x1ebAQID".to_string()), (status, body));
+    }
+
+    #[tokio::test]
+    async fn polling_metrics() {
+        let (tx, _rx) = mpsc::channel(16);
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let sessions = SessionStore::new().with_metrics(metrics.clone());
+        let state = PollingState::with_sessions(
+            ChannelResponder::new(tx),
+            PollingConfig::default(),
+            sessions,
+        );
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        assert_eq!(1, metrics.active_sessions());
+        let uri = format!("/?EIO=4&transport=polling&sid={}", sid.as_str());
+
+        let (_, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!(1, metrics.packets_sent(&PacketType::Open));
+        assert_eq!(body.len() as u64, metrics.total_bytes_sent());
+
+        request(&router, Method::POST, uri, "4hello\x1e4world").await;
+        assert_eq!(2, metrics.packets_received(&PacketType::Message));
+        assert_eq!(13, metrics.total_bytes_received());
+    }
+
+    #[tokio::test]
+    async fn polling_handshake_without_sid() {
+        let (tx, _rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let uri = "/?EIO=4&transport=polling".to_string();
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.starts_with('0'));
+        let handshake: Value = serde_json::from_str(&body[1..]).unwrap();
+        let sid = Sid::new(handshake["sid"].as_str().unwrap().to_string()).unwrap();
+        assert!(state.sessions().get(&sid).is_ok());
+        assert_eq!(1, state.sessions().len());
+    }
+
+    #[tokio::test]
+    async fn connection_states() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx.clone()));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            ChannelResponder::new(tx),
+            sid.clone(),
+            state.sessions(),
+        )
+        .unwrap();
+        assert_eq!(ConnectionState::Opening, engine.state());
+
+        let uri = format!("/?EIO=4&transport=polling&sid={}", sid.as_str());
+        request(&router, Method::GET, uri, "").await;
+        assert_eq!(ConnectionState::Open, engine.state());
+        assert_eq!(TransportKind::Polling, engine.transport_kind());
+
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async {
+            peer.send(Frame::Text(Packet::ping_probe().to_string()))
+                .await
+                .unwrap();
+            peer.recv().await.unwrap().unwrap();
+            assert!(engine.is_upgrading());
+            assert_eq!(TransportKind::Polling, engine.transport_kind());
+
+            peer.send(Frame::Text(Packet::upgrade().to_string()))
+                .await
+                .unwrap();
+            peer.send(Frame::Text("4hello".to_string())).await.unwrap();
+            rx.recv().await.unwrap();
+            assert_eq!(ConnectionState::Open, engine.state());
+            assert_eq!(TransportKind::Websocket, engine.transport_kind());
+
+            engine.shutdown();
+            assert_eq!(ConnectionState::Closing, engine.state());
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+        assert_eq!(ConnectionState::Closed, engine.state());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upgrade_flushes_held_poll() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx.clone()));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?EIO=4&transport=polling&sid={}", sid.as_str());
+        request(&router, Method::GET, uri.clone(), "").await;
+        let engine = Engine::with_sid(
+            TransportType::Websocket(WebsocketTransport::default()),
+            ChannelResponder::new(tx),
+            sid,
+            state.sessions(),
+        )
+        .unwrap();
+
+        let start = tokio::time::Instant::now();
+        let (io, mut peer) = ChannelIo::pair();
+        let client = async {
+            // the GET is held open first, then the probe is exchanged
+            let probe = async {
+                peer.send(Frame::Text(Packet::ping_probe().to_string()))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    Frame::Text(Packet::pong_probe().to_string()),
+                    peer.recv().await.unwrap().unwrap()
+                );
+            };
+            let (poll, _) = tokio::join!(request(&router, Method::GET, uri, ""), probe);
+            // the held GET returns a Noop before its poll timeout
+            assert_eq!((StatusCode::OK, "6".to_string()), poll);
+            assert_eq!(Duration::ZERO, start.elapsed());
+
+            peer.send(Frame::Text(Packet::upgrade().to_string()))
+                .await
+                .unwrap();
+            peer.send(Frame::Text("4hello".to_string())).await.unwrap();
+            rx.recv().await.unwrap();
+            engine.shutdown();
+            // the Noop is not sent over the websocket
+            assert_eq!(
+                Frame::Text(Packet::close().to_string()),
+                peer.recv().await.unwrap().unwrap()
+            );
+        };
+        let (res, _) = tokio::join!(engine.run(io), client);
+        assert!(res.is_ok());
+    }
+
+    /// Only accepts the clients passing the token "secret"
+    struct TokenResponder;
+
+    #[async_trait]
+    impl Responder for TokenResponder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![]
+        }
+
+        async fn authenticate(&self, req: &HandshakeRequest) -> Result<(), AuthRejection> {
+            match req.query("token") {
+                Some("secret") => Ok(()),
+                Some(_) => Err(AuthRejection::Forbidden),
+                None => Err(AuthRejection::Unauthorized),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_handshake_authentication() {
+        let state = PollingState::new(TokenResponder);
+        let router = polling_router(state.clone());
+        let uri = "/?EIO=4&transport=polling".to_string();
+        let (status, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+        let error: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json!({"code": 4, "message": "Unauthorized"}), error);
+        let (status, _) = request(&router, Method::GET, format!("{}&token=guess", uri), "").await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+        // no session is allocated for the rejected clients
+        assert!(state.sessions().is_empty());
+
+        let (status, body) =
+            request(&router, Method::GET, format!("{}&token=secret", uri), "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.starts_with('0'));
+        assert_eq!(1, state.sessions().len());
+    }
+
+    /// Replies to every payload with a pong message
+    struct ReplyResponder;
+
+    #[async_trait]
+    impl Responder for ReplyResponder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![Packet::message_text("pong"), Packet::message_text("again")]
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_returns_responder_replies() {
+        let state = PollingState::new(ReplyResponder);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        state.sessions().get(&sid).unwrap().drain();
+        let uri = format!("/?sid={}", sid.as_str());
+        request(&router, Method::POST, uri.clone(), "4ping").await;
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!(
+            (StatusCode::OK, "4pong\x1e4again".to_string()),
+            (status, body)
+        );
+    }
+
+    #[tokio::test]
+    async fn polling_batches_up_to_max_payload() {
+        let (tx, _rx) = mpsc::channel(16);
+        let mut config = PollingConfig::default();
+        config.handshake.max_payload = 13;
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let session = state.sessions().get(&sid).unwrap();
+        session.drain();
+        for msg in ["hello", "world", "again"] {
+            session.send(Packet::message_text(msg)).await.unwrap();
+        }
+        let uri = format!("/?sid={}", sid.as_str());
+        let (_, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!("4hello\x1e4world", body);
+        let (_, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!("4again", body);
+    }
+
+    #[test]
+    fn jsonp_escaping() {
+        assert_eq!(r#"___eio[0]("4hello");"#, encode_jsonp("0", "4hello"));
+        assert_eq!(
+            r#"___eio[12]("4\"quoted\" \\ back\nslash");"#,
+            encode_jsonp("12", "4\"quoted\" \\ back\nslash")
+        );
+        assert_eq!(
+            r#"___eio[3]("4a\u2028b\u2029c");"#,
+            encode_jsonp("3", "4a\u{2028}b\u{2029}c")
+        );
+        assert_eq!(r#"___eio[11]("4x");"#, encode_jsonp("1);alert(1", "4x"));
+    }
+
+    #[tokio::test]
+    async fn polling_jsonp_get() {
+        let (tx, _rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        state.sessions().get(&sid).unwrap().drain();
+        state
+            .sessions()
+            .get(&sid)
+            .unwrap()
+            .send(Packet::message_text("hi"))
+            .await
+            .unwrap();
+
+        let req = Request::builder()
+            .uri(format!("/?sid={}&j=2", sid.as_str()))
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(
+            "text/javascript; charset=UTF-8",
+            res.headers()[header::CONTENT_TYPE]
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&b"___eio[2](\"4hi\");"[..], &body[..]);
+
+        state
+            .sessions()
+            .get(&sid)
+            .unwrap()
+            .send(Packet::message_text("plain"))
+            .await
+            .unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!((StatusCode::OK, "4plain".to_string()), (status, body));
+    }
+
+    #[tokio::test]
+    async fn polling_shutdown_returns_close() {
+        let (tx, _rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        request(&router, Method::GET, uri.clone(), "").await;
+
+        state.sessions().shutdown();
+        let (status, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!((StatusCode::OK, "1".to_string()), (status, body));
+        assert!(state.sessions().is_empty());
+        let (status, _) = request(&router, Method::GET, uri, "").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[tokio::test]
+    async fn polling_content_type() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let session = state.sessions().get(&sid).unwrap();
+        session.drain();
+        let uri = format!("/?sid={}", sid.as_str());
+        let get = || {
+            Request::builder()
+                .uri(uri.clone())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        session.send(Packet::message_text("hello")).await.unwrap();
+        let res = router.clone().oneshot(get()).await.unwrap();
+        assert_eq!(
+            "text/plain; charset=UTF-8",
+            res.headers()[header::CONTENT_TYPE]
+        );
+        session
+            .send(Packet::message_binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        let res = router.clone().oneshot(get()).await.unwrap();
+        assert_eq!(
+            "application/octet-stream",
+            res.headers()[header::CONTENT_TYPE]
+        );
+
+        for content_type in ["text/plain; charset=UTF-8", "application/octet-stream"] {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(uri.clone())
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from("bAQID"))
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let received = rx.recv().await.unwrap();
+            assert_eq!(
+                &[Packet::message_binary(vec![1, 2, 3])],
+                received.payload.packets()
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polling_get_times_out_with_noop() {
+        let (tx, _rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        // the Open packet is queued, so the first GET returns promptly
+        let start = tokio::time::Instant::now();
+        let (_, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert!(body.starts_with('0'));
+        assert_eq!(Duration::ZERO, start.elapsed());
+
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!((StatusCode::OK, "6".to_string()), (status, body));
+        assert_eq!(PollingConfig::default().poll_timeout, start.elapsed());
+    }
+
+    /// Generates the same sid for every session
+    #[derive(Debug)]
+    struct FixedSidGenerator(&'static str);
+
+    impl SidGenerator for FixedSidGenerator {
+        fn generate(&self) -> Sid {
+            Sid::new(self.0.to_string()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_handshake_sid_generator() {
+        let (tx, _rx) = mpsc::channel(16);
+        let config = PollingConfig {
+            sid_generator: Arc::new(FixedSidGenerator("shard-1")),
+            ..PollingConfig::default()
+        };
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+        let handshake = "/?EIO=4&transport=polling".to_string();
+        let (status, body) = request(&router, Method::GET, handshake.clone(), "").await;
+        assert_eq!(StatusCode::OK, status);
+        let open: Value = serde_json::from_str(&body[1..]).unwrap();
+        assert_eq!("shard-1", open["sid"]);
+
+        // the generated sid of an open session is not given to another client
+        let (status, body) = request(&router, Method::GET, handshake, "").await;
+        assert_eq!(
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "code": 3, "message": "A session already exists for the sid" })
+            ),
+            (status, serde_json::from_str(&body).unwrap())
+        );
+        assert_eq!(1, state.sessions().len());
+    }
+
+    #[tokio::test]
+    async fn polling_max_connections() {
+        let (tx, _rx) = mpsc::channel(16);
+        let sessions = SessionStore::new().with_max_connections(2);
+        let state = PollingState::with_sessions(
+            ChannelResponder::new(tx),
+            PollingConfig::default(),
+            sessions,
+        );
+        let router = polling_router(state.clone());
+        let handshake = "/?EIO=4&transport=polling".to_string();
+        let mut sids = vec![];
+        for _ in 0..2 {
+            let (status, body) = request(&router, Method::GET, handshake.clone(), "").await;
+            assert_eq!(StatusCode::OK, status);
+            let open: Value = serde_json::from_str(&body[1..]).unwrap();
+            sids.push(Sid::new(open["sid"].as_str().unwrap().to_string()).unwrap());
+        }
+
+        // the third connection is refused until a session is evicted
+        let (status, body) = request(&router, Method::GET, handshake.clone(), "").await;
+        assert_eq!(
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": 3,
+                    "message": "The server reached its maximum number of connections",
+                })
+            ),
+            (status, serde_json::from_str(&body).unwrap())
+        );
+        assert!(matches!(
+            state.open_session(),
+            Err(EngineError::TooManyConnections)
+        ));
+        assert_eq!(2, state.sessions().len());
+
+        state.sessions().disconnect(&sids[0]);
+        let (status, body) = request(&router, Method::GET, handshake, "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.starts_with('0'));
+        assert_eq!(2, state.sessions().len());
+    }
+
+    #[tokio::test]
+    async fn polling_per_packet_dispatch() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let config = PollingConfig {
+            per_packet_dispatch: true,
+            ..PollingConfig::default()
+        };
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        let (status, _) = request(&router, Method::POST, uri, "4one\x1e4two\x1e4three").await;
+        assert_eq!(StatusCode::OK, status);
+        for text in ["one", "two", "three"] {
+            let received = rx.recv().await.unwrap();
+            assert_eq!(sid, received.sid);
+            assert_eq!(&[Packet::message_text(text)], received.payload.packets());
+        }
+        assert!(rx.try_recv().is_err());
+
+        // by default, the whole payload is dispatched at once
+        let (tx, mut rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        request(&router, Method::POST, uri, "4one\x1e4two\x1e4three").await;
+        assert_eq!(3, rx.recv().await.unwrap().payload.packets().len());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polling_session_times_out_without_requests() {
+        let (tx, _rx) = mpsc::channel(16);
+        let (evicted_tx, mut evicted_rx) = mpsc::unbounded_channel();
+        let sessions = SessionStore::new().on_disconnect(move |sid: &Sid| {
+            evicted_tx.send(sid.clone()).unwrap();
+        });
+        let mut config = PollingConfig::default();
+        config.handshake.ping_interval = Duration::from_millis(100);
+        config.handshake.ping_timeout = Duration::from_millis(50);
+        config.poll_timeout = Duration::from_millis(20);
+        let state = PollingState::with_sessions(ChannelResponder::new(tx), config, sessions);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+
+        // requests arriving within ping_interval + ping_timeout keep the session alive
+        for _ in 0..5 {
+            let (status, _) = request(&router, Method::GET, uri.clone(), "").await;
+            assert_eq!(StatusCode::OK, status);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let (status, _) = request(&router, Method::POST, uri.clone(), "4hi").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(evicted_rx.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_millis(160)).await;
+        assert_eq!(sid, evicted_rx.recv().await.unwrap());
+        assert!(state.sessions().get(&sid).is_err());
+        let (status, _) = request(&router, Method::GET, uri, "").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[tokio::test]
+    async fn polling_open_with_configured_upgrades() {
+        let (tx, _rx) = mpsc::channel(16);
+        let mut config = PollingConfig::default();
+        config.handshake.upgrades = vec![];
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?EIO=4&transport=polling&sid={}", sid.as_str());
+        let (_, body) = request(&router, Method::GET, uri, "").await;
+        let handshake: Value = serde_json::from_str(&body[1..]).unwrap();
+        assert_eq!(json!([]), handshake["upgrades"]);
+    }
+
+    #[tokio::test]
+    async fn polling_cors_preflight() {
+        let (tx, _rx) = mpsc::channel(16);
+        let config = PollingConfig {
+            cors: CorsConfig {
+                allowed_origins: vec!["https://example.com".to_string()],
+                allow_credentials: true,
+            },
+            ..PollingConfig::default()
+        };
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/?EIO=4&transport=polling")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = router
+            .clone()
+            .oneshot(preflight("https://example.com"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let headers = res.headers();
+        assert_eq!(
+            "https://example.com",
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!("true", headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+        assert_eq!("GET, POST", headers[header::ACCESS_CONTROL_ALLOW_METHODS]);
+
+        let res = router
+            .clone()
+            .oneshot(preflight("https://evil.com"))
+            .await
+            .unwrap();
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        // the actual requests carry the headers too
+        let sid = state.open_session().unwrap();
+        let req = Request::builder()
+            .uri(format!("/?sid={}", sid.as_str()))
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "https://example.com",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+    }
+
+    #[tokio::test]
+    async fn polling_no_cors_by_default() {
+        let (tx, _rx) = mpsc::channel(16);
+        let router = polling_router(PollingState::new(ChannelResponder::new(tx)));
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.oneshot(req).await.unwrap();
+        assert!(res.headers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn polling_post_over_max_http_buffer_size() {
+        let (tx, _rx) = mpsc::channel(16);
+        let config = PollingConfig {
+            max_http_buffer_size: 8,
+            ..PollingConfig::default()
+        };
+        let state = PollingState::with_config(ChannelResponder::new(tx), config);
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+        let uri = format!("/?sid={}", sid.as_str());
+        let too_large = |len: usize| {
+            (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({
+                    "code": 3,
+                    "message": format!(
+                        "Received a polling body of {} bytes, over the maximum HTTP buffer size",
+                        len
+                    ),
+                }),
+            )
+        };
+
+        // a Content-Length over the limit is rejected before reading the body
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .header(header::CONTENT_LENGTH, "1000")
+            .body(Body::from("4hi"))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            too_large(1000),
+            (status, serde_json::from_slice(&body).unwrap())
+        );
+
+        // without a Content-Length, the body is rejected once its chunks are over the limit,
+        // while the client is still sending it
+        let (mut sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .body(body)
+            .unwrap();
+        let client = async {
+            sender.send_data("4hello".into()).await.unwrap();
+            sender.send_data("world".into()).await.unwrap();
+            sender
+        };
+        let (res, _sender) = tokio::join!(router.clone().oneshot(req), client);
+        let res = res.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            too_large(11),
+            (status, serde_json::from_slice(&body).unwrap())
+        );
+
+        // a chunked body at the limit is accepted
+        let (mut sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .body(body)
+            .unwrap();
+        let client = async move {
+            sender.send_data("4hel".into()).await.unwrap();
+            sender.send_data("lo!!".into()).await.unwrap();
+        };
+        let (res, _) = tokio::join!(router.oneshot(req), client);
+        assert_eq!(StatusCode::OK, res.unwrap().status());
+    }
+
+    #[tokio::test]
+    async fn polling_bad_requests() {
+        let (tx, _rx) = mpsc::channel(16);
+        let state = PollingState::new(ChannelResponder::new(tx));
+        let router = polling_router(state.clone());
+        let sid = state.open_session().unwrap();
+
+        let error = |code: u8, message: &str| {
+            (
+                StatusCode::BAD_REQUEST,
+                json!({ "code": code, "message": message }),
+            )
+        };
+        let uri = format!("/?sid={}", sid.as_str());
+        let cases = [
+            (
+                "4hello\x1e\x1e4world",
+                error(3, "Encountered a packet parsing error"),
+            ),
+            ("2probe", error(3, "Received ping packet from client")),
+            ("3probe", error(3, "Received pong packet with data")),
+        ];
+        for (body, expected) in cases {
+            let (status, body) = request(&router, Method::POST, uri.clone(), body).await;
+            assert_eq!(expected, (status, serde_json::from_str(&body).unwrap()));
+        }
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .body(Body::from(&b"4\xff"[..]))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            error(3, "Encountered a packet parsing error"),
+            (status, serde_json::from_slice(&body).unwrap())
+        );
+
+        let unknown = error(1, "Session ID unknown");
+        let (status, body) = request(&router, Method::GET, "/?sid=unknown".to_string(), "").await;
+        assert_eq!(unknown, (status, serde_json::from_str(&body).unwrap()));
+        let (status, body) =
+            request(&router, Method::POST, "/?sid=unknown".to_string(), "4hello").await;
+        assert_eq!(unknown, (status, serde_json::from_str(&body).unwrap()));
+
+        let unknown = error(0, "Transport unknown");
+        let uri = format!("/?sid={}&transport=flashsocket", sid.as_str());
+        let (status, body) = request(&router, Method::GET, uri.clone(), "").await;
+        assert_eq!(unknown, (status, serde_json::from_str(&body).unwrap()));
+        let (status, body) = request(&router, Method::POST, uri, "4hello").await;
+        assert_eq!(unknown, (status, serde_json::from_str(&body).unwrap()));
+
+        // the handshake of clients before V4 is refused
+        let unsupported = error(5, "Unsupported protocol version");
+        for uri in ["/?EIO=3&transport=polling", "/?transport=polling"] {
+            let (status, body) = request(&router, Method::GET, uri.to_string(), "").await;
+            assert_eq!(unsupported, (status, serde_json::from_str(&body).unwrap()));
+        }
+        let uri = "/?EIO=4&transport=flashsocket".to_string();
+        let (status, body) = request(&router, Method::GET, uri, "").await;
+        assert_eq!(unknown, (status, serde_json::from_str(&body).unwrap()));
+        assert_eq!(1, state.sessions().len());
+    }
+
+    #[test]
+    fn polling_query_transport() {
+        let query = |transport: Option<&str>| PollingQuery {
+            sid: None,
+            j: None,
+            transport: transport.map(str::to_string),
+        };
+        assert_eq!(None, query(None).transport_kind().unwrap());
+        assert_eq!(
+            Some(TransportKind::Polling),
+            query(Some("polling")).transport_kind().unwrap()
+        );
+        assert_eq!(
+            Some(TransportKind::Websocket),
+            query(Some("websocket")).transport_kind().unwrap()
+        );
+        assert!(matches!(
+            query(Some("xhr")).transport_kind(),
+            Err(EngineError::UnknownTransport)
+        ));
+    }
+}
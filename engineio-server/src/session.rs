@@ -0,0 +1,638 @@
+use crate::engine::{DisconnectReason, EngineError, Sid};
+use crate::metrics::Metrics;
+use crate::transport::PollingTransport;
+use eio_parser::Packet;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// What happens when a packet is sent to a session whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Waits until the client takes the queued packets
+    #[default]
+    Block,
+    /// Drops the oldest queued packet to make room
+    DropOldest,
+    /// Terminates the session, as the client cannot keep up
+    Disconnect,
+}
+
+/// The `QueueConfig` struct bounds the packets waiting to be sent to the client of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// The maximum number of queued packets
+    pub capacity: NonZeroUsize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: NonZeroUsize::new(1024).unwrap(),
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// The lifecycle of a connection, as driven by the polling requests and `Engine::run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    /// The session was created, but the Open packet has not been polled yet
+    #[default]
+    Opening,
+    Open,
+    /// The client sent the probe of the upgrade to the websocket, and the upgrade packet is awaited
+    Upgrading,
+    /// The session was terminated, but is not removed from the store yet
+    Closing,
+    /// The session was removed from the store
+    Closed,
+}
+
+/// A `Session` holds the state of a connection that outlives a single request,
+/// such as the packets waiting to be sent to the client.
+#[derive(Debug)]
+pub struct Session {
+    sid: Sid,
+    outbound: Mutex<VecDeque<Packet>>,
+    queue: QueueConfig,
+    /// Notified when packets are queued
+    queued: Notify,
+    /// Notified when queued packets are taken
+    taken: Notify,
+    upgraded: AtomicBool,
+    terminated: AtomicBool,
+    close_reason: Mutex<Option<DisconnectReason>>,
+    state: Mutex<ConnectionState>,
+    /// When the last request of the client arrived, for the liveness of polling sessions
+    last_seen: Mutex<Instant>,
+    shutdown: Notify,
+    disconnect_timer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Session {
+    fn new(sid: Sid, queue: QueueConfig) -> Session {
+        Session {
+            sid,
+            outbound: Mutex::new(VecDeque::new()),
+            queue,
+            queued: Notify::new(),
+            taken: Notify::new(),
+            upgraded: AtomicBool::new(false),
+            terminated: AtomicBool::new(false),
+            close_reason: Mutex::new(None),
+            state: Mutex::new(ConnectionState::Opening),
+            last_seen: Mutex::new(Instant::now()),
+            shutdown: Notify::new(),
+            disconnect_timer: Mutex::new(None),
+        }
+    }
+
+    pub fn sid(&self) -> &Sid {
+        &self.sid
+    }
+
+    /// Queues a packet to be sent to the client. When the queue is full, the overflow policy
+    /// either waits for room, drops the oldest packet, or terminates the session
+    /// and returns `EngineError::OutboundQueueFull`.
+    /// Sending to a terminated session, or waiting for room once it is terminated,
+    /// returns `EngineError::SessionClosed`.
+    pub async fn send(&self, packet: Packet) -> Result<(), EngineError> {
+        let capacity = self.queue.capacity.get();
+        loop {
+            let taken = self.taken.notified();
+            if self.is_terminated() {
+                return Err(EngineError::SessionClosed);
+            }
+            {
+                let mut outbound = self.outbound.lock().unwrap();
+                if outbound.len() >= capacity {
+                    match self.queue.policy {
+                        OverflowPolicy::Block => {}
+                        OverflowPolicy::DropOldest => {
+                            outbound.pop_front();
+                        }
+                        OverflowPolicy::Disconnect => {
+                            drop(outbound);
+                            self.terminate();
+                            return Err(EngineError::OutboundQueueFull);
+                        }
+                    }
+                }
+                if outbound.len() < capacity {
+                    outbound.push_back(packet);
+                    break;
+                }
+            }
+            taken.await;
+        }
+        self.queued.notify_waiters();
+        Ok(())
+    }
+
+    /// Queues a control packet, such as the Open or Close packet, regardless of the capacity
+    pub(crate) fn send_control(&self, packet: Packet) {
+        self.outbound.lock().unwrap().push_back(packet);
+        self.queued.notify_waiters();
+    }
+
+    /// Waits for at least one queued packet, then returns all of the queued packets
+    pub async fn recv(&self) -> Vec<Packet> {
+        loop {
+            let queued = self.queued.notified();
+            let packets = self.take_all();
+            if !packets.is_empty() {
+                return packets;
+            }
+            queued.await;
+        }
+    }
+
+    /// Same as `recv`, but only returns the packets fitting in a polling payload of `max` bytes,
+    /// leaving the others queued for the next request
+    pub async fn recv_up_to(&self, max: usize) -> Vec<Packet> {
+        loop {
+            let queued = self.queued.notified();
+            let packets = self.take_up_to(max);
+            if !packets.is_empty() {
+                return packets;
+            }
+            queued.await;
+        }
+    }
+
+    /// Same as `recv`, but returns no packets if none is queued before the timeout
+    pub async fn recv_timeout(&self, timeout: Duration) -> Vec<Packet> {
+        time::timeout(timeout, self.recv())
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Returns all of the queued packets, without waiting
+    pub fn drain(&self) -> Vec<Packet> {
+        self.take_all()
+    }
+
+    /// Same as `drain`, but only returns the packets fitting in a polling payload of `max` bytes
+    pub fn drain_up_to(&self, max: usize) -> Vec<Packet> {
+        self.take_up_to(max)
+    }
+
+    pub(crate) fn take_up_to(&self, max: usize) -> Vec<Packet> {
+        let payload = PollingTransport.drain_up_to(&mut self.outbound.lock().unwrap(), max);
+        if !payload.is_empty() {
+            self.taken.notify_waiters();
+        }
+        payload.into_iter().collect()
+    }
+
+    fn take_all(&self) -> Vec<Packet> {
+        let packets: Vec<Packet> = self.outbound.lock().unwrap().drain(..).collect();
+        if !packets.is_empty() {
+            self.taken.notify_waiters();
+        }
+        packets
+    }
+
+    /// Whether the session has been upgraded from polling to websocket
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded.load(Ordering::SeqCst)
+    }
+
+    pub fn mark_upgraded(&self) {
+        self.upgraded.store(true, Ordering::SeqCst);
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves the session to the given state. Once closing, the session can only be closed.
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        let mut current = self.state.lock().unwrap();
+        let closing = matches!(*current, ConnectionState::Closing | ConnectionState::Closed);
+        if !closing || state == ConnectionState::Closed {
+            *current = state;
+        }
+    }
+
+    /// Records that a request of the client arrived, which keeps a polling session alive
+    pub fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().unwrap()
+    }
+
+    /// Terminates the session. A Close packet is queued for the next polling request,
+    /// while the websocket loop of an upgraded session is woken up to send it.
+    /// The senders waiting for room in the queue are woken up to give up.
+    pub fn terminate(&self) {
+        if !self.terminated.swap(true, Ordering::SeqCst) {
+            self.set_state(ConnectionState::Closing);
+            if !self.is_upgraded() {
+                self.send_control(Packet::close());
+            }
+            self.shutdown.notify_waiters();
+            self.taken.notify_waiters();
+        }
+    }
+
+    /// Terminates the session for the given reason, which the websocket loop of an upgraded
+    /// session sends in the Close frame. The reason of a terminated session is not replaced.
+    pub fn close(&self, reason: DisconnectReason) {
+        if !self.is_terminated() {
+            self.close_reason.lock().unwrap().get_or_insert(reason);
+        }
+        self.terminate();
+    }
+
+    /// The reason given to `Session::close`, if the session was closed with one
+    pub fn close_reason(&self) -> Option<DisconnectReason> {
+        *self.close_reason.lock().unwrap()
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.terminated.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the session is terminated
+    pub async fn terminated(&self) {
+        let notified = self.shutdown.notified();
+        if self.is_terminated() {
+            return;
+        }
+        notified.await
+    }
+
+    /// Stops the pending eviction of the session, if the transport was lost
+    fn cancel_disconnect(&self) {
+        if let Some(timer) = self.disconnect_timer.lock().unwrap().take() {
+            timer.abort();
+        }
+    }
+}
+
+type DisconnectCallback = Arc<dyn Fn(&Sid) + Send + Sync>;
+
+/// The `SessionStore` maps each sid to its session, so that the engines created
+/// for each polling request can share the state of a connection.
+/// Cloning the store is cheap, and the clones refer to the same sessions.
+///
+/// When the transport of a session is lost, the session survives for the grace period,
+/// so that the client can reconnect with the same sid. Otherwise it is evicted,
+/// and the disconnect callback is called with its sid.
+#[derive(Clone, Default)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<Sid, Arc<Session>>>>,
+    grace_period: Duration,
+    on_disconnect: Option<DisconnectCallback>,
+    queue: QueueConfig,
+    metrics: Option<Arc<dyn Metrics>>,
+    max_connections: Option<usize>,
+}
+
+/// The metrics of a store without any, ignoring every event
+#[derive(Debug)]
+struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+impl fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionStore")
+            .field("sessions", &self.sessions)
+            .field("grace_period", &self.grace_period)
+            .field("queue", &self.queue)
+            .field("metrics", &self.metrics)
+            .field("max_connections", &self.max_connections)
+            .finish()
+    }
+}
+
+impl SessionStore {
+    pub fn new() -> SessionStore {
+        SessionStore::default()
+    }
+
+    /// Sets how long a session survives the loss of its transport, which is zero by default
+    pub fn with_grace_period(mut self, grace_period: Duration) -> SessionStore {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets the bound of the outbound queue of each new session
+    pub fn with_queue(mut self, queue: QueueConfig) -> SessionStore {
+        self.queue = queue;
+        self
+    }
+
+    /// Sets the metrics notified of the sessions of the store, and the traffic of their engines
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> SessionStore {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Limits the number of sessions opened by `try_insert`, which is unlimited by default.
+    /// The sessions evicted after a disconnect free up their slot, while upgrading a session
+    /// to the websocket does not take another one.
+    pub fn with_max_connections(mut self, max_connections: usize) -> SessionStore {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn metrics(&self) -> &dyn Metrics {
+        self.metrics.as_deref().unwrap_or(&NoopMetrics)
+    }
+
+    /// Sets the callback called with the sid of each session evicted after a disconnect
+    pub fn on_disconnect<F>(mut self, callback: F) -> SessionStore
+    where
+        F: Fn(&Sid) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Arc::new(callback));
+        self
+    }
+
+    /// Creates a new session for the sid, replacing any existing one, even beyond `max_connections`
+    pub fn insert(&self, sid: Sid) -> Arc<Session> {
+        let session = Arc::new(Session::new(sid.clone(), self.queue));
+        let previous = self.sessions.lock().unwrap().insert(sid, session.clone());
+        if previous.is_none() {
+            self.metrics().session_opened();
+        }
+        session
+    }
+
+    /// Creates a new session for the sid, unless the store already holds `max_connections`
+    /// sessions, in which case `EngineError::TooManyConnections` is returned. Unlike `insert`,
+    /// an existing session is never replaced, but refused with `EngineError::SidInUse`.
+    pub fn try_insert(&self, sid: Sid) -> Result<Arc<Session>, EngineError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.contains_key(&sid) {
+            return Err(EngineError::SidInUse);
+        }
+        if self
+            .max_connections
+            .is_some_and(|max| sessions.len() >= max)
+        {
+            return Err(EngineError::TooManyConnections);
+        }
+        let session = Arc::new(Session::new(sid.clone(), self.queue));
+        sessions.insert(sid, session.clone());
+        self.metrics().session_opened();
+        Ok(session)
+    }
+
+    /// Looks up the session of a request. A session whose transport was lost is resumed,
+    /// as the client reconnected within the grace period.
+    pub fn get(&self, sid: &Sid) -> Result<Arc<Session>, EngineError> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(sid)
+            .cloned()
+            .ok_or(EngineError::UnknownSession)?;
+        session.cancel_disconnect();
+        session.touch();
+        Ok(session)
+    }
+
+    /// Evicts the session if none of its requests arrives within `timeout`, since the server only
+    /// knows that a polling client is alive from its requests. The timer is reset by each request
+    /// looking up the session with `get`, and stops once the session is upgraded to the websocket,
+    /// whose heartbeat takes over, or removed.
+    pub fn watch_liveness(&self, sid: &Sid, timeout: Duration) {
+        let store = self.clone();
+        let sid = sid.clone();
+        tokio::spawn(async move {
+            loop {
+                let deadline = match store.sessions.lock().unwrap().get(&sid) {
+                    Some(session) if !session.is_upgraded() => session.last_seen() + timeout,
+                    _ => return,
+                };
+                if Instant::now() >= deadline {
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(sid = sid.as_str(), "polling session timed out");
+                    store.evict(&sid);
+                    return;
+                }
+                time::sleep_until(deadline).await;
+            }
+        });
+    }
+
+    /// Starts the grace timer of a session whose transport was lost,
+    /// evicting it right away if there is no grace period
+    pub fn disconnect(&self, sid: &Sid) {
+        let session = match self.sessions.lock().unwrap().get(sid) {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        if self.grace_period.is_zero() {
+            self.evict(sid);
+            return;
+        }
+        let store = self.clone();
+        let sid = sid.clone();
+        let timer = tokio::spawn(async move {
+            time::sleep(store.grace_period).await;
+            store.evict(&sid);
+        });
+        let previous = session.disconnect_timer.lock().unwrap().replace(timer);
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+    }
+
+    fn evict(&self, sid: &Sid) {
+        if self.remove(sid).is_some() {
+            if let Some(callback) = &self.on_disconnect {
+                callback(sid);
+            }
+        }
+    }
+
+    pub fn remove(&self, sid: &Sid) -> Option<Arc<Session>> {
+        let session = self.sessions.lock().unwrap().remove(sid);
+        if let Some(session) = &session {
+            session.set_state(ConnectionState::Closed);
+            self.metrics().session_closed();
+        }
+        session
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Terminates every session, e.g. when the server shuts down
+    pub fn shutdown(&self) {
+        for session in self.sessions.lock().unwrap().values() {
+            session.terminate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn session_shared_across_store_clones() {
+        let store = SessionStore::new();
+        let sid = Sid::generate();
+        let session = store.insert(sid.clone());
+        // a second request looks up the session from its own handle to the store
+        let other = store.clone().get(&sid).unwrap();
+        let packet = Packet::message_text("hello");
+        other.send(packet.clone()).await.unwrap();
+        assert_eq!(vec![packet], session.recv().await);
+        assert_eq!(1, store.len());
+        assert!(store.remove(&sid).is_some());
+        assert!(matches!(store.get(&sid), Err(EngineError::UnknownSession)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_queues_close_packets() {
+        let store = SessionStore::new();
+        let first = store.insert(Sid::generate());
+        let second = store.insert(Sid::generate());
+        store.shutdown();
+        // terminating twice does not queue a second Close packet
+        first.terminate();
+        for session in [first, second] {
+            assert!(session.is_terminated());
+            session.terminated().await;
+            assert_eq!(vec![Packet::close()], session.drain());
+        }
+    }
+
+    fn disconnect_channel(grace_period: Duration) -> (SessionStore, mpsc::UnboundedReceiver<Sid>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let store = SessionStore::new()
+            .with_grace_period(grace_period)
+            .on_disconnect(move |sid| tx.send(sid.clone()).unwrap());
+        (store, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_within_grace_period() {
+        let grace_period = Duration::from_secs(10);
+        let (store, mut disconnects) = disconnect_channel(grace_period);
+        let sid = store.insert(Sid::generate()).sid().clone();
+        store.disconnect(&sid);
+        time::sleep(grace_period / 2).await;
+        assert!(store.get(&sid).is_ok());
+        time::sleep(grace_period).await;
+        assert!(store.get(&sid).is_ok());
+        assert!(disconnects.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicted_after_grace_period() {
+        let grace_period = Duration::from_secs(10);
+        let (store, mut disconnects) = disconnect_channel(grace_period);
+        let sid = store.insert(Sid::generate()).sid().clone();
+        store.disconnect(&sid);
+        time::sleep(grace_period * 2).await;
+        assert_eq!(Some(sid.clone()), disconnects.recv().await);
+        assert!(matches!(store.get(&sid), Err(EngineError::UnknownSession)));
+    }
+
+    fn bounded_session(policy: OverflowPolicy) -> Arc<Session> {
+        let queue = QueueConfig {
+            capacity: NonZeroUsize::new(2).unwrap(),
+            policy,
+        };
+        SessionStore::new()
+            .with_queue(queue)
+            .insert(Sid::generate())
+    }
+
+    #[tokio::test]
+    async fn full_queue_blocks() {
+        let session = bounded_session(OverflowPolicy::Block);
+        for i in 0..2 {
+            session
+                .send(Packet::message_text(i.to_string()))
+                .await
+                .unwrap();
+        }
+        let blocked = session.send(Packet::message_text("2"));
+        tokio::pin!(blocked);
+        assert!(futures::poll!(blocked.as_mut()).is_pending());
+        assert_eq!(2, session.drain().len());
+        blocked.await.unwrap();
+        assert_eq!(vec![Packet::message_text("2")], session.recv().await);
+    }
+
+    #[tokio::test]
+    async fn blocked_send_returns_once_terminated() {
+        let session = bounded_session(OverflowPolicy::Block);
+        for i in 0..2 {
+            session
+                .send(Packet::message_text(i.to_string()))
+                .await
+                .unwrap();
+        }
+        let blocked = session.send(Packet::message_text("2"));
+        tokio::pin!(blocked);
+        assert!(futures::poll!(blocked.as_mut()).is_pending());
+        session.terminate();
+        assert!(matches!(blocked.await, Err(EngineError::SessionClosed)));
+        assert!(matches!(
+            session.send(Packet::message_text("3")).await,
+            Err(EngineError::SessionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_oldest() {
+        let session = bounded_session(OverflowPolicy::DropOldest);
+        for i in 0..3 {
+            session
+                .send(Packet::message_text(i.to_string()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            vec![Packet::message_text("1"), Packet::message_text("2")],
+            session.recv().await
+        );
+    }
+
+    #[tokio::test]
+    async fn full_queue_disconnects() {
+        let session = bounded_session(OverflowPolicy::Disconnect);
+        for i in 0..2 {
+            session
+                .send(Packet::message_text(i.to_string()))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            session.send(Packet::message_text("2")).await,
+            Err(EngineError::OutboundQueueFull)
+        ));
+        assert!(session.is_terminated());
+        // the Close packet is queued after the packets the client has not received yet
+        let packets = session.recv().await;
+        assert_eq!(Some(&Packet::close()), packets.last());
+        assert_eq!(3, packets.len());
+    }
+}
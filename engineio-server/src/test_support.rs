@@ -0,0 +1,139 @@
+//! Test doubles shared by the tests of the websocket path
+
+use crate::engine::EngineError;
+use crate::io::{Frame, TransportIo};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A scripted websocket: `recv` returns the inbound frames pushed by the test, in order, then
+/// reports the connection as closed, unless it is kept open. The frames sent by the engine are
+/// captured in the `SentFrames` returned by `new`, since the engine takes the socket by value.
+#[derive(Debug)]
+pub struct MockWebSocket {
+    inbound: VecDeque<Frame>,
+    sent: SentFrames,
+    keep_open: bool,
+    permessage_deflate: bool,
+}
+
+impl MockWebSocket {
+    pub fn new(inbound: impl IntoIterator<Item = Frame>) -> (MockWebSocket, SentFrames) {
+        let sent = SentFrames::default();
+        let socket = MockWebSocket {
+            inbound: inbound.into_iter().collect(),
+            sent: sent.clone(),
+            keep_open: false,
+            permessage_deflate: false,
+        };
+        (socket, sent)
+    }
+
+    /// Makes `recv` wait forever once the inbound frames are consumed, rather than reporting
+    /// the connection as closed, e.g. to let the heartbeat run with the tokio time paused
+    pub fn keep_open(mut self) -> MockWebSocket {
+        self.keep_open = true;
+        self
+    }
+
+    /// Reports `permessage-deflate` as negotiated, so that compressed messages are exchanged
+    pub fn permessage_deflate(mut self) -> MockWebSocket {
+        self.permessage_deflate = true;
+        self
+    }
+
+    /// Pushes a text frame to be received after the others
+    pub fn push_text(&mut self, text: impl Into<String>) {
+        self.inbound.push_back(Frame::Text(text.into()));
+    }
+}
+
+#[async_trait]
+impl TransportIo for MockWebSocket {
+    async fn recv(&mut self) -> Option<Result<Frame, EngineError>> {
+        match self.inbound.pop_front() {
+            Some(frame) => Some(Ok(frame)),
+            None if self.keep_open => futures::future::pending().await,
+            None => None,
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), EngineError> {
+        self.sent.frames.lock().unwrap().push(frame);
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        *self.sent.closed.lock().unwrap() = true;
+    }
+
+    fn permessage_deflate(&self) -> bool {
+        self.permessage_deflate
+    }
+}
+
+/// The frames sent to a `MockWebSocket`, shared with the test
+#[derive(Debug, Clone, Default)]
+pub struct SentFrames {
+    frames: Arc<Mutex<Vec<Frame>>>,
+    closed: Arc<Mutex<bool>>,
+}
+
+impl SentFrames {
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// Whether the engine closed the socket
+    pub fn is_closed(&self) -> bool {
+        *self.closed.lock().unwrap()
+    }
+
+    /// Asserts that exactly these frames were sent, in order
+    #[track_caller]
+    pub fn assert_frames(&self, expected: &[Frame]) {
+        assert_eq!(expected, &self.frames()[..]);
+    }
+
+    /// Asserts that exactly these text frames were sent, in order, e.g. `&["3probe", "6"]`
+    #[track_caller]
+    pub fn assert_texts(&self, expected: &[&str]) {
+        let expected: Vec<Frame> = expected
+            .iter()
+            .map(|text| Frame::Text(text.to_string()))
+            .collect();
+        self.assert_frames(&expected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn mock_websocket() {
+        let (mut socket, sent) = MockWebSocket::new([Frame::Text("4hello".to_string())]);
+        socket.push_text("4world");
+        for text in ["4hello", "4world"] {
+            let frame = socket.recv().await.unwrap().unwrap();
+            assert_eq!(Frame::Text(text.to_string()), frame);
+        }
+        assert!(socket.recv().await.is_none());
+
+        socket
+            .send(Frame::Text("3probe".to_string()))
+            .await
+            .unwrap();
+        socket.send(Frame::Binary(vec![1])).await.unwrap();
+        sent.assert_frames(&[Frame::Text("3probe".to_string()), Frame::Binary(vec![1])]);
+        assert!(!sent.is_closed());
+        socket.close().await;
+        assert!(sent.is_closed());
+
+        // a socket kept open waits for frames that never come
+        let (socket, _) = MockWebSocket::new([]);
+        let mut socket = socket.keep_open();
+        assert!(socket.recv().now_or_never().is_none());
+    }
+}
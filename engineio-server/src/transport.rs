@@ -1,16 +1,54 @@
+use crate::io::Frame;
 use eio_parser::*;
+use std::collections::VecDeque;
 use thiserror::Error;
 
 #[derive(Debug, Error, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum TransportParsingError {
     #[error("Encountered a packet parsing error")]
-    PacketParsingErr(#[source] PacketParsingError),
+    PacketParsingErr(#[from] PacketParsingError),
     #[error("Expected single packet, but received {0}")]
     InvalidPayloadForWebsocket(usize),
     #[error("Received pong packet with data")]
     InvalidPongPacket,
     #[error("Received ping packet from client")]
     InvalidPingPacket,
+    #[error("Received upgrade packet outside of the websocket upgrade")]
+    InvalidUpgradePacket,
+    #[error("Received noop packet from client")]
+    InvalidNoopPacket,
+    #[error("Received open packet from client")]
+    InvalidOpenPacket,
+}
+
+/// Checks that a client is allowed to send the packet to the responder, whichever the transport.
+/// The Open and Noop packets are only sent by the server, the upgrade packet is handled by the
+/// upgrade itself, and the probes are only exchanged during the upgrade.
+pub(crate) fn check_client_packet(packet: &Packet) -> Result<(), TransportParsingError> {
+    match packet.packet_type() {
+        // only heartbeat pongs are expected, probes are sent over the websocket
+        PacketType::Pong if !packet.is_heartbeat_pong() => {
+            Err(TransportParsingError::InvalidPongPacket)
+        }
+        // we are not supposed to receive ping packets from client
+        PacketType::Ping if !packet.is_heartbeat_ping() => {
+            Err(TransportParsingError::InvalidPingPacket)
+        }
+        PacketType::Upgrade => Err(TransportParsingError::InvalidUpgradePacket),
+        // noop packets are only sent by the server, to end a pending poll
+        PacketType::Noop => Err(TransportParsingError::InvalidNoopPacket),
+        PacketType::Open => Err(TransportParsingError::InvalidOpenPacket),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum TransportEncodingError {
+    #[error("Encountered a packet encoding error")]
+    PacketEncodingErr(#[from] PacketEncodingError),
+    #[error("Expected single packet, but received {0}")]
+    InvalidPayloadForWebsocket(usize),
 }
 
 #[derive(Debug)]
@@ -19,30 +57,79 @@ pub enum TransportType {
     Polling(PollingTransport),
 }
 
+impl TransportType {
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            TransportType::Websocket(_) => TransportKind::Websocket,
+            TransportType::Polling(_) => TransportKind::Polling,
+        }
+    }
+}
+
 pub trait Transport {
     fn parse_payload(&self, payload_msg: &str) -> Result<Payload, TransportParsingError>;
+    fn encode_payload(&self, payload: &Payload) -> Result<Vec<u8>, TransportEncodingError>;
 }
 
-#[derive(Debug)]
-pub struct WebsocketTransport;
+/// The websocket transport, where each frame carries a single packet
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WebsocketTransport {
+    /// Accepts the frames of clients erroneously sending several packets separated by the record
+    /// separator, splitting them into their packets rather than rejecting them. Off by default.
+    pub lenient: bool,
+}
+
+impl WebsocketTransport {
+    /// Encodes a packet into a websocket frame. Binary messages are sent as the raw bytes
+    /// in a binary frame, as base64 is only needed by the polling transport.
+    pub fn encode_packet(&self, packet: &Packet) -> Frame {
+        match packet.data() {
+            Some(PacketData::Binary(bytes)) => Frame::Binary(bytes.clone()),
+            _ => Frame::Text(packet.to_string()),
+        }
+    }
+
+    /// Decodes a binary frame, which always carries the raw bytes of a binary message
+    pub fn parse_binary(&self, bytes: Vec<u8>) -> Payload {
+        Payload::single(Packet::message_binary(bytes))
+    }
+}
 
 impl Transport for WebsocketTransport {
     // when upgrading from transport polling transport, client sends a ping packet with data "probe"
     // e.g. "2probe". Server is supposed to respond with 3probe. From then on, the server is only
     // one who sends the ping packet with no data e.g. "2", while the client can only respond with
     // the pong packet e.g. "3"
+    /// A websocket frame must contain exactly one packet, so an empty frame is rejected
+    /// with `InvalidPayloadForWebsocket(0)` and a frame with several packets with their count,
+    /// unless the transport is `lenient`.
     fn parse_payload(&self, payload_msg: &str) -> Result<Payload, TransportParsingError> {
-        match Payload::try_from(payload_msg) {
-            Ok(payload) => {
-                if payload.len() > 1 {
-                    Err(TransportParsingError::InvalidPayloadForWebsocket(
-                        payload.len(),
-                    ))
-                } else {
-                    Ok(payload)
-                }
-            }
-            Err(parsing_err) => Err(TransportParsingError::PacketParsingErr(parsing_err)),
+        if payload_msg.is_empty() {
+            return Err(TransportParsingError::InvalidPayloadForWebsocket(0));
+        }
+        let payload = Payload::try_from(payload_msg)?;
+        if payload.len() != 1 && !self.lenient {
+            return Err(TransportParsingError::InvalidPayloadForWebsocket(
+                payload.len(),
+            ));
+        }
+        #[cfg(feature = "tracing")]
+        if payload.len() > 1 {
+            tracing::warn!(
+                packets = payload.len(),
+                "split a websocket frame carrying several packets"
+            );
+        }
+        Ok(payload)
+    }
+
+    /// Encodes the single packet of a websocket frame, with binary data as the raw bytes
+    fn encode_payload(&self, payload: &Payload) -> Result<Vec<u8>, TransportEncodingError> {
+        match payload.packets() {
+            [packet] => Ok(self.encode_packet(packet).into_data()),
+            packets => Err(TransportEncodingError::InvalidPayloadForWebsocket(
+                packets.len(),
+            )),
         }
     }
 }
@@ -50,30 +137,281 @@ impl Transport for WebsocketTransport {
 #[derive(Debug)]
 pub struct PollingTransport;
 
-impl Transport for PollingTransport {
-    fn parse_payload(&self, payload_msg: &str) -> Result<Payload, TransportParsingError> {
-        match Payload::try_from(payload_msg) {
-            Ok(payload) => {
-                for p in payload.packets() {
-                    match p.get_packet_type() {
-                        PacketType::Pong => {
-                            // check that packet has no data
-                            if let Some(_) = p.get_packet_data() {
-                                return Err(TransportParsingError::InvalidPongPacket);
-                            }
-                        }
-                        PacketType::Ping => {
-                            // we are not supposed to receive ping packets from client
-                            if let Some(_) = p.get_packet_data() {
-                                return Err(TransportParsingError::InvalidPingPacket);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                Ok(payload)
+impl PollingTransport {
+    /// Encodes the packets into the body of a polling response, separating each packet
+    /// with the record separator. Binary data is base64 encoded and prefixed with a 'b'.
+    pub fn encode_packets(&self, packets: &[Packet]) -> String {
+        packets
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(PACKET_SEPARATOR)
+    }
+
+    /// Takes the packets at the front of the queue whose encoded payload fits in `max` bytes,
+    /// e.g. the `maxPayload` of the handshake, leaving the others for the next poll.
+    /// The first packet is always taken, even if it alone exceeds the limit.
+    pub fn drain_up_to(&self, queue: &mut VecDeque<Packet>, max: usize) -> Payload {
+        let mut payload = Payload::from_packets(Vec::new());
+        let mut len = 0;
+        while let Some(packet) = queue.front() {
+            let separator = usize::from(!payload.is_empty());
+            let next = len + separator + packet.byte_len(TransportKind::Polling);
+            if !payload.is_empty() && next > max {
+                break;
             }
-            Err(parsing_err) => Err(TransportParsingError::PacketParsingErr(parsing_err)),
+            len = next;
+            payload.push(queue.pop_front().expect("the queue has a front packet"));
         }
+        payload
+    }
+}
+
+impl Transport for PollingTransport {
+    /// Some clients end the body with a separator, so a single trailing separator is ignored,
+    /// while empty packets anywhere else are still rejected
+    fn parse_payload(&self, payload_msg: &str) -> Result<Payload, TransportParsingError> {
+        let payload_msg = payload_msg
+            .strip_suffix(PACKET_SEPARATOR)
+            .unwrap_or(payload_msg);
+        let payload = Payload::try_from(payload_msg)?;
+        payload.validate()?;
+        payload.iter().try_for_each(check_client_packet)?;
+        Ok(payload)
+    }
+
+    /// Encodes the packets separated by the record separator, with binary data as base64
+    fn encode_payload(&self, payload: &Payload) -> Result<Vec<u8>, TransportEncodingError> {
+        Ok(payload.try_encode()?.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_parsing_error_conversion() {
+        let err = TransportParsingError::from(PacketParsingError::InvalidChar);
+        assert_eq!(
+            TransportParsingError::PacketParsingErr(PacketParsingError::InvalidChar),
+            err
+        );
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(
+            Some(&PacketParsingError::InvalidChar),
+            source.downcast_ref::<PacketParsingError>()
+        );
+        let payload_err = Payload::try_from("9").unwrap_err();
+        assert_eq!(
+            Err(TransportParsingError::PacketParsingErr(payload_err)),
+            PollingTransport.parse_payload("9")
+        );
+    }
+
+    #[test]
+    fn packet_parsing_error_source_chain() {
+        let err = PollingTransport
+            .parse_payload("4hello\x1e2foo")
+            .unwrap_err();
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(
+            Some(&PacketParsingError::InvalidPayloadPacket {
+                index: 1,
+                source: Box::new(PacketParsingError::InvalidPing)
+            }),
+            source.downcast_ref::<PacketParsingError>()
+        );
+        // the packet of the payload is reachable from the transport error
+        let packet_err = source.source().unwrap();
+        assert_eq!(
+            Some(&PacketParsingError::InvalidPing),
+            packet_err.downcast_ref::<PacketParsingError>()
+        );
+        assert!(std::error::Error::source(&TransportParsingError::InvalidPingPacket).is_none());
+    }
+
+    #[test]
+    fn transport_type_kind() {
+        assert_eq!(
+            TransportKind::Polling,
+            TransportType::Polling(PollingTransport).kind()
+        );
+        assert_eq!(
+            TransportKind::Websocket,
+            TransportType::Websocket(WebsocketTransport::default()).kind()
+        );
+    }
+
+    #[test]
+    fn polling_drain_up_to() {
+        let mut queue: VecDeque<Packet> = vec![
+            Packet::message_text("hello"),
+            Packet::message_text("world"),
+            Packet::message_binary(vec![1, 2, 3]),
+            Packet::ping(),
+        ]
+        .into();
+        // "4hello" and "4world" take 13 bytes with the separator, "bAQID" would need 6 more
+        let payload = PollingTransport.drain_up_to(&mut queue, 18);
+        assert_eq!("4hello\x1e4world", payload.encode());
+        assert_eq!(2, queue.len());
+
+        // exactly at the limit
+        let payload = PollingTransport.drain_up_to(&mut queue, 7);
+        assert_eq!("bAQID\x1e2", payload.encode());
+        assert!(queue.is_empty());
+
+        // a packet larger than the limit is still taken alone
+        queue.push_back(Packet::message_text("too long"));
+        queue.push_back(Packet::noop());
+        let payload = PollingTransport.drain_up_to(&mut queue, 4);
+        assert_eq!("4too long", payload.encode());
+        assert_eq!(1, queue.len());
+        assert_eq!(1, PollingTransport.drain_up_to(&mut queue, 4).len());
+        assert!(PollingTransport.drain_up_to(&mut queue, 4).is_empty());
+    }
+
+    #[test]
+    fn polling_rejects_server_only_packets() {
+        assert_eq!(
+            Err(TransportParsingError::InvalidUpgradePacket),
+            PollingTransport.parse_payload("5")
+        );
+        assert_eq!(
+            Err(TransportParsingError::InvalidNoopPacket),
+            PollingTransport.parse_payload("6")
+        );
+        assert_eq!(
+            Err(TransportParsingError::InvalidNoopPacket),
+            PollingTransport.parse_payload("4hello\x1e6")
+        );
+    }
+
+    #[test]
+    fn polling_trailing_separator() {
+        assert_eq!(
+            Ok(Payload::single(Packet::message_text("hello"))),
+            PollingTransport.parse_payload("4hello\x1e")
+        );
+        assert_eq!(
+            Err(TransportParsingError::PacketParsingErr(
+                PacketParsingError::EmptySegment(1)
+            )),
+            PollingTransport.parse_payload("4hello\x1e\x1e4world")
+        );
+        assert_eq!(
+            Err(TransportParsingError::PacketParsingErr(
+                PacketParsingError::EmptySegment(1)
+            )),
+            PollingTransport.parse_payload("4hello\x1e\x1e")
+        );
+        // websocket frames carry a single packet, without any separator
+        assert!(WebsocketTransport::default()
+            .parse_payload("4hello\x1e")
+            .is_err());
+    }
+
+    #[test]
+    fn polling_rejects_misplaced_open() {
+        assert_eq!(
+            Err(TransportParsingError::PacketParsingErr(
+                PacketParsingError::UnexpectedOpen(1)
+            )),
+            PollingTransport.parse_payload("0{}\x1e0{}")
+        );
+        assert_eq!(
+            Err(TransportParsingError::PacketParsingErr(
+                PacketParsingError::UnexpectedOpen(1)
+            )),
+            PollingTransport.parse_payload("4hello\x1e0{}")
+        );
+    }
+
+    #[test]
+    fn websocket_frame_single_packet() {
+        assert_eq!(
+            Err(TransportParsingError::InvalidPayloadForWebsocket(0)),
+            WebsocketTransport::default().parse_payload("")
+        );
+        assert_eq!(
+            Err(TransportParsingError::InvalidPayloadForWebsocket(2)),
+            WebsocketTransport::default().parse_payload("4hello\x1e4world")
+        );
+        let payload = WebsocketTransport::default()
+            .parse_payload("4hello")
+            .unwrap();
+        assert_eq!(&[Packet::message_text("hello")], payload.packets());
+
+        let lenient = WebsocketTransport { lenient: true };
+        let payload = lenient.parse_payload("4hello\x1e4world").unwrap();
+        assert_eq!(
+            &[Packet::message_text("hello"), Packet::message_text("world")],
+            payload.packets()
+        );
+        assert_eq!(
+            Err(TransportParsingError::InvalidPayloadForWebsocket(0)),
+            lenient.parse_payload("")
+        );
+    }
+
+    #[test]
+    fn binary_message_encoding_per_transport() {
+        let packet = Packet::message_binary(vec![1, 2, 3]);
+        assert_eq!(
+            "bAQID",
+            PollingTransport.encode_packets(std::slice::from_ref(&packet))
+        );
+        let msg = WebsocketTransport::default().encode_packet(&packet);
+        assert_eq!(Frame::Binary(vec![1, 2, 3]), msg);
+        let payload = WebsocketTransport::default().parse_binary(vec![1, 2, 3]);
+        assert_eq!(&[packet], payload.packets());
+        assert_eq!(
+            Frame::Text("4hello".to_string()),
+            WebsocketTransport::default().encode_packet(&Packet::message_text("hello"))
+        );
+    }
+
+    #[test]
+    fn binary_payload_encoding_per_transport() {
+        let payload = Payload::from(vec![Packet::message_binary(vec![1, 2, 3])]);
+        assert_eq!(
+            Ok(b"bAQID".to_vec()),
+            PollingTransport.encode_payload(&payload)
+        );
+        assert_eq!(
+            Ok(vec![1, 2, 3]),
+            WebsocketTransport::default().encode_payload(&payload)
+        );
+
+        let payload = Payload::from(vec![Packet::message_text("a"), Packet::noop()]);
+        assert_eq!(
+            Ok(b"4a\x1e6".to_vec()),
+            PollingTransport.encode_payload(&payload)
+        );
+        assert_eq!(
+            Err(TransportEncodingError::InvalidPayloadForWebsocket(2)),
+            WebsocketTransport::default().encode_payload(&payload)
+        );
+        let payload = Payload::from(vec![Packet::message_text("a\x1eb")]);
+        assert_eq!(
+            Err(TransportEncodingError::PacketEncodingErr(
+                PacketEncodingError::SeparatorInPacket(0)
+            )),
+            PollingTransport.encode_payload(&payload)
+        );
+    }
+
+    #[test]
+    fn polling_mixed_payload_round_trip() {
+        let packets = vec![
+            Packet::message_text("hello"),
+            Packet::message_binary(vec![1, 2, 3]),
+        ];
+        let body = PollingTransport.encode_packets(&packets);
+        assert_eq!("4hello\x1ebAQID", body);
+        let payload = PollingTransport.parse_payload(&body).unwrap();
+        assert_eq!(packets.as_slice(), payload.packets());
+        assert_eq!(body, PollingTransport.encode_packets(payload.packets()));
     }
 }
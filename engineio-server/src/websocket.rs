@@ -0,0 +1,395 @@
+use crate::engine::*;
+use crate::handshake::{HandshakeConfig, HandshakeRequest};
+use crate::io::AxumIo;
+use crate::polling::PollingQuery;
+use crate::session::SessionStore;
+use crate::transport::*;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{Extension, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{AddExtensionLayer, Router};
+use eio_parser::ProtocolVersion;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The websocket subprotocol of engine.io, which clients may offer in `Sec-WebSocket-Protocol`
+pub const ENGINEIO_SUBPROTOCOL: &str = "engineio";
+
+/// The `WebsocketConfig` struct holds the settings of the websocket upgrade request,
+/// which `websocket_router` applies to each upgrade
+#[derive(Debug, Clone, Default)]
+pub struct WebsocketConfig {
+    /// Rejects the upgrade of clients that do not offer the engine.io subprotocol
+    pub require_subprotocol: bool,
+    /// The handshake of the engines serving the websockets, which should be the one of the
+    /// `PollingConfig` of the sessions upgraded from polling
+    pub handshake: HandshakeConfig,
+}
+
+impl WebsocketConfig {
+    /// Negotiates the subprotocol of an upgrade request. The engine.io subprotocol is echoed
+    /// back when the client offers it, while a client not offering it is rejected with
+    /// 400 Bad Request if `require_subprotocol` is set. It is called by `websocket_router`,
+    /// and only needs to be called by custom upgrade handlers.
+    pub fn negotiate(
+        &self,
+        ws: WebSocketUpgrade,
+        headers: &HeaderMap,
+    ) -> Result<WebSocketUpgrade, StatusCode> {
+        if self.require_subprotocol && !offers_subprotocol(headers) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(ws.protocols([ENGINEIO_SUBPROTOCOL]))
+    }
+}
+
+/// The `WebsocketState` is shared by the websocket handler across upgrade requests.
+/// It holds the responder, along with the store of sessions and the websocket configuration.
+#[derive(Debug)]
+pub struct WebsocketState<R: Responder> {
+    responder: Arc<R>,
+    sessions: SessionStore,
+    config: WebsocketConfig,
+}
+
+impl<R: Responder> WebsocketState<R> {
+    pub fn new(responder: R) -> Arc<WebsocketState<R>> {
+        WebsocketState::with_config(responder, WebsocketConfig::default())
+    }
+
+    pub fn with_config(responder: R, config: WebsocketConfig) -> Arc<WebsocketState<R>> {
+        WebsocketState::with_sessions(responder, config, SessionStore::new())
+    }
+
+    /// Creates the state with a configured store, which must be the store of the `PollingState`
+    /// for the sessions upgraded from polling to be found
+    pub fn with_sessions(
+        responder: R,
+        config: WebsocketConfig,
+        sessions: SessionStore,
+    ) -> Arc<WebsocketState<R>> {
+        Arc::new(WebsocketState {
+            responder: Arc::new(responder),
+            sessions,
+            config,
+        })
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    /// Creates the engine serving the websocket of the session, or opens a new session for a
+    /// client connecting without polling first, see `Engine::new`
+    fn engine(&self, sid: Option<String>) -> Result<Engine<Arc<R>>, EngineError> {
+        let builder = EngineBuilder::new().handshake(self.config.handshake.clone());
+        let transport = TransportType::Websocket(WebsocketTransport::default());
+        match sid {
+            Some(sid) => builder.build_with_sid(
+                transport,
+                self.responder.clone(),
+                Sid::new(sid)?,
+                &self.sessions,
+            ),
+            None => builder.build(transport, self.responder.clone(), &self.sessions),
+        }
+    }
+}
+
+/// Removes the session opened by a websocket handshake whose upgrade never completes,
+/// as `Engine::run` is what removes it otherwise
+struct PendingSession {
+    sessions: SessionStore,
+    sid: Option<Sid>,
+}
+
+impl PendingSession {
+    fn upgraded(mut self) {
+        self.sid = None;
+    }
+}
+
+impl Drop for PendingSession {
+    fn drop(&mut self) {
+        if let Some(sid) = self.sid.take() {
+            self.sessions.remove(&sid);
+        }
+    }
+}
+
+/// Builds the router serving the websocket transport, with the sid passed as a query parameter
+pub fn websocket_router<R: Responder + 'static>(state: Arc<WebsocketState<R>>) -> Router {
+    Router::new()
+        .route("/", get(websocket_upgrade::<R>))
+        .layer(AddExtensionLayer::new(state))
+}
+
+/// The upgrade request is authenticated with `Responder::authenticate`, then its subprotocol
+/// is negotiated with `WebsocketConfig::negotiate`, before the engine of the session is created.
+/// A request without a sid is the handshake of a client connecting without polling first,
+/// which opens a new session, e.g. refused with 503 once the store is at its `max_connections`.
+/// Any failure refuses the upgrade with an error response, and the accepted websocket is
+/// served by `Engine::run` until it is closed. Since the websocket of axum joins the fragments of
+/// each message itself, the `max_message_size` of the engine is enforced by the upgrade, which
+/// closes the connection of a client sending a larger message.
+async fn websocket_upgrade<R: Responder + 'static>(
+    Extension(state): Extension<Arc<WebsocketState<R>>>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    Query(query): Query<PollingQuery>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => return EngineError::from(rejection).into_response(),
+    };
+    let request = HandshakeRequest::new(params, headers);
+    // only V4 clients are accepted by the handshake, as by the polling one
+    if query.sid.is_none() {
+        if let Err(err) = request.validate(&[ProtocolVersion::V4]) {
+            return err.into_response();
+        }
+    }
+    if let Err(rejection) = state.responder.authenticate(&request).await {
+        return rejection.into_response();
+    }
+    let ws = match state.config.negotiate(ws, request.headers()) {
+        Ok(ws) => ws,
+        Err(status) => return status.into_response(),
+    };
+    let opens_session = query.sid.is_none();
+    let engine = match state.engine(query.sid) {
+        Ok(engine) => engine,
+        Err(err) => return err.into_response(),
+    };
+    let pending = PendingSession {
+        sessions: state.sessions.clone(),
+        sid: engine
+            .session()
+            .filter(|_| opens_session)
+            .map(|session| session.sid().clone()),
+    };
+    ws.max_message_size(engine.max_message_size())
+        .on_upgrade(move |socket| async move {
+            pending.upgraded();
+            let _ = engine.run(AxumIo::new(socket)).await;
+        })
+        .into_response()
+}
+
+/// Whether the comma separated `Sec-WebSocket-Protocol` headers offer the engine.io subprotocol
+pub fn offers_subprotocol(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == ENGINEIO_SUBPROTOCOL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::AuthRejection;
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use axum::extract::{FromRequest, RequestParts};
+    use axum::http::header::HeaderName;
+    use axum::http::{HeaderValue, Request};
+    use eio_parser::Packet;
+    use tower::ServiceExt;
+
+    /// Refuses the clients with a revoked token
+    struct TokenResponder;
+
+    #[async_trait]
+    impl Responder for TokenResponder {
+        async fn process_packet(&self, _packet: ResponderPayload) -> Vec<Packet> {
+            vec![]
+        }
+
+        async fn authenticate(&self, req: &HandshakeRequest) -> Result<(), AuthRejection> {
+            match req.query("token") {
+                Some("revoked") => Err(AuthRejection::Forbidden),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// An upgrade request, along with the upgrade handle hyper gives to the router
+    fn upgrade_request(uri: String, headers: &[(HeaderName, &'static str)]) -> Request<Body> {
+        let mut req = Request::builder()
+            .uri(uri)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        req.extensions_mut().insert(on_upgrade);
+        req
+    }
+
+    async fn upgrade_status(router: &Router, uri: String) -> StatusCode {
+        router
+            .clone()
+            .oneshot(upgrade_request(uri, &[]))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn router_authenticates_upgrades() {
+        let state = WebsocketState::new(TokenResponder);
+        let sid = state.sessions().insert(Sid::generate()).sid().clone();
+        let router = websocket_router(state);
+        let uri = format!("/?EIO=4&transport=websocket&sid={}", sid.as_str());
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            upgrade_status(&router, format!("{}&token=revoked", uri)).await
+        );
+        assert_eq!(
+            StatusCode::SWITCHING_PROTOCOLS,
+            upgrade_status(&router, format!("{}&token=valid", uri)).await
+        );
+        // the session must exist
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            upgrade_status(
+                &router,
+                "/?EIO=4&transport=websocket&sid=unknown".to_string()
+            )
+            .await
+        );
+    }
+
+    #[test]
+    fn subprotocol_offers() {
+        let mut headers = HeaderMap::new();
+        assert!(!offers_subprotocol(&headers));
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("graphql-ws"),
+        );
+        assert!(!offers_subprotocol(&headers));
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("graphql-ws, engineio"),
+        );
+        assert!(offers_subprotocol(&headers));
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("engineio-v2"),
+        );
+        headers.append(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("engineio"),
+        );
+        assert!(offers_subprotocol(&headers));
+    }
+
+    #[tokio::test]
+    async fn router_negotiates_subprotocol() {
+        let config = WebsocketConfig {
+            require_subprotocol: true,
+            ..WebsocketConfig::default()
+        };
+        let state = WebsocketState::with_config(TokenResponder, config);
+        let sessions = state.sessions().clone();
+        let router = websocket_router(state);
+        let uri = |sid: &Sid| format!("/?EIO=4&transport=websocket&sid={}", sid.as_str());
+
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        let res = router
+            .clone()
+            .oneshot(upgrade_request(
+                uri(&sid),
+                &[(header::SEC_WEBSOCKET_PROTOCOL, "engineio")],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, res.status());
+        assert_eq!(
+            Some(&HeaderValue::from_static("engineio")),
+            res.headers().get(header::SEC_WEBSOCKET_PROTOCOL)
+        );
+        // the upgrade is refused without the subprotocol
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            upgrade_status(&router, uri(&sid)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn router_refuses_handshake_at_max_connections() {
+        let sessions = SessionStore::new().with_max_connections(1);
+        let state =
+            WebsocketState::with_sessions(TokenResponder, WebsocketConfig::default(), sessions);
+        let sessions = state.sessions().clone();
+        let router = websocket_router(state);
+        let handshake = "/?EIO=4&transport=websocket".to_string();
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        let res = router
+            .clone()
+            .oneshot(upgrade_request(handshake.clone(), &[]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            &br#"{"code":3,"message":"The server reached its maximum number of connections"}"#[..],
+            &body[..]
+        );
+        // the slot of a removed session is free again
+        sessions.remove(&sid);
+        assert_eq!(
+            StatusCode::SWITCHING_PROTOCOLS,
+            upgrade_status(&router, handshake).await
+        );
+        // the handshake is only accepted from V4 clients
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            upgrade_status(&router, "/?EIO=3&transport=websocket".to_string()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_session_removed_without_upgrade() {
+        let sessions = SessionStore::new();
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        drop(PendingSession {
+            sessions: sessions.clone(),
+            sid: Some(sid),
+        });
+        assert!(sessions.is_empty());
+        let sid = sessions.insert(Sid::generate()).sid().clone();
+        PendingSession {
+            sessions: sessions.clone(),
+            sid: Some(sid),
+        }
+        .upgraded();
+        assert_eq!(1, sessions.len());
+    }
+
+    #[tokio::test]
+    async fn failed_upgrade() {
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let rejection = WebSocketUpgrade::from_request(&mut RequestParts::new(req))
+            .await
+            .unwrap_err();
+        let err = EngineError::from(rejection);
+        assert!(matches!(
+            err,
+            EngineError::WebsocketUpgrade(WebSocketUpgradeRejection::InvalidConnectionHeader(_))
+        ));
+        assert_eq!(3, err.code());
+    }
+}
@@ -0,0 +1,400 @@
+//! Drives a server on a local port through the sequence of a real engine.io client:
+//! the polling handshake, a message echoed over polling, the upgrade to the websocket,
+//! and the close. Run with `cargo test -p engineio-server --features integration`.
+#![cfg(feature = "integration")]
+
+use async_trait::async_trait;
+use axum::http::{header, StatusCode};
+use eio_parser::{Packet, PacketType};
+use engineio_server::*;
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Client, Method, Request};
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+/// Sends every message packet back to the client, and refuses the clients with a revoked token
+#[derive(Debug, Clone)]
+struct EchoResponder;
+
+#[async_trait]
+impl Responder for EchoResponder {
+    async fn process_packet(&self, packet: ResponderPayload) -> Vec<Packet> {
+        packet
+            .payload
+            .into_iter()
+            .filter(|p| p.packet_type() == &PacketType::Message)
+            .collect()
+    }
+
+    async fn authenticate(&self, req: &HandshakeRequest) -> Result<(), AuthRejection> {
+        match req.query("token") {
+            Some("revoked") => Err(AuthRejection::Forbidden),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A server listening on an ephemeral port, shut down by `TestServer::stop`
+struct TestServer {
+    addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<hyper::Result<()>>,
+}
+
+impl TestServer {
+    fn start() -> TestServer {
+        TestServer::with_websocket(WebsocketConfig::default())
+    }
+
+    fn with_websocket(config: WebsocketConfig) -> TestServer {
+        TestServer::with_config(PollingConfig::default(), config)
+    }
+
+    fn with_config(polling: PollingConfig, config: WebsocketConfig) -> TestServer {
+        let sessions = SessionStore::new();
+        let state = PollingState::with_sessions(EchoResponder, polling, sessions.clone());
+        let websocket = WebsocketState::with_sessions(EchoResponder, config, sessions);
+        let app = polling_router(state).nest("/websocket", websocket_router(websocket));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        let handle = tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        }));
+        TestServer {
+            addr,
+            shutdown,
+            handle,
+        }
+    }
+
+    /// Performs the polling handshake, returning the sid of the new session
+    async fn open_session(&self) -> String {
+        let uri = format!("http://{}/?EIO=4&transport=polling", self.addr);
+        let (_, body) = request(Method::GET, uri, "").await;
+        let handshake: Value = serde_json::from_str(&body[1..]).unwrap();
+        handshake["sid"].as_str().unwrap().to_string()
+    }
+
+    fn websocket_url(&self, sid: &str) -> String {
+        format!(
+            "ws://{}/websocket?EIO=4&transport=websocket&sid={}",
+            self.addr, sid
+        )
+    }
+
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        self.handle.await.unwrap().unwrap();
+    }
+}
+
+async fn request(method: Method, uri: String, body: &str) -> (StatusCode, String) {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = Client::new().request(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn client_sequence() {
+    let server = TestServer::start();
+    let base = format!("http://{}/?EIO=4&transport=polling", server.addr);
+
+    // the handshake returns the Open packet with the sid of the new session
+    let (status, body) = request(Method::GET, base.clone(), "").await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(body.starts_with('0'));
+    let handshake: Value = serde_json::from_str(&body[1..]).unwrap();
+    assert_eq!(serde_json::json!(["websocket"]), handshake["upgrades"]);
+    let sid = handshake["sid"].as_str().unwrap().to_string();
+    let polling = format!("{}&sid={}", base, sid);
+
+    // a message posted over polling is echoed on the next poll
+    let (status, body) = request(Method::POST, polling.clone(), "4hello").await;
+    assert_eq!((StatusCode::OK, "ok".to_string()), (status, body));
+    let (status, body) = request(Method::GET, polling.clone(), "").await;
+    assert_eq!((StatusCode::OK, "4hello".to_string()), (status, body));
+
+    // the upgrade probe, followed by the upgrade packet
+    let url = format!(
+        "ws://{}/websocket?EIO=4&transport=websocket&sid={}",
+        server.addr, sid
+    );
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    ws.send(WsMessage::Text(Packet::ping_probe().to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        WsMessage::Text(Packet::pong_probe().to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+    ws.send(WsMessage::Text(Packet::upgrade().to_string()))
+        .await
+        .unwrap();
+
+    // messages are now echoed over the websocket
+    ws.send(WsMessage::Text("4world".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        WsMessage::Text("4world".to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+
+    // once the websocket is closed, the session is gone
+    ws.send(WsMessage::Close(None)).await.unwrap();
+    while let Some(Ok(_)) = ws.next().await {}
+    let (status, _) = request(Method::GET, polling, "").await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn subprotocol_negotiation() {
+    let server = TestServer::with_websocket(WebsocketConfig {
+        require_subprotocol: true,
+        ..WebsocketConfig::default()
+    });
+
+    // the offered subprotocol is echoed back
+    let sid = server.open_session().await;
+    let mut req = server.websocket_url(&sid).into_client_request().unwrap();
+    req.headers_mut()
+        .insert(header::SEC_WEBSOCKET_PROTOCOL, "engineio".parse().unwrap());
+    let (_, res) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(
+        Some("engineio"),
+        res.headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+    );
+
+    // the upgrade is rejected without the subprotocol
+    let sid = server.open_session().await;
+    match tokio_tungstenite::connect_async(server.websocket_url(&sid)).await {
+        Err(WsError::Http(res)) => assert_eq!(StatusCode::BAD_REQUEST, res.status()),
+        res => panic!(
+            "expected a rejected upgrade, got {:?}",
+            res.map(|(_, res)| res)
+        ),
+    }
+    server.stop().await;
+
+    // the subprotocol is optional by default
+    let server = TestServer::start();
+    let sid = server.open_session().await;
+    let (_, res) = tokio_tungstenite::connect_async(server.websocket_url(&sid))
+        .await
+        .unwrap();
+    assert!(res.headers().get(header::SEC_WEBSOCKET_PROTOCOL).is_none());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn failed_upgrade() {
+    let server = TestServer::start();
+    let sid = server.open_session().await;
+    // a plain GET without the upgrade headers is rejected with the engine.io error
+    let url = server.websocket_url(&sid).replacen("ws://", "http://", 1);
+    let (status, body) = request(Method::GET, url, "").await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        serde_json::json!({
+            "code": 3,
+            "message": "Failed to upgrade the request to a websocket",
+        }),
+        body
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn websocket_control_frames() {
+    let server = TestServer::start();
+    let sid = server.open_session().await;
+    let (mut ws, _) = tokio_tungstenite::connect_async(server.websocket_url(&sid))
+        .await
+        .unwrap();
+
+    // a websocket Ping is answered with a Pong control frame, even during the upgrade, and its
+    // data is not mistaken for the engine.io probe
+    ws.send(WsMessage::Ping(b"2probe".to_vec())).await.unwrap();
+    assert_eq!(
+        WsMessage::Pong(b"2probe".to_vec()),
+        ws.next().await.unwrap().unwrap()
+    );
+    ws.send(WsMessage::Text(Packet::ping_probe().to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        WsMessage::Text(Packet::pong_probe().to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+    ws.send(WsMessage::Text(Packet::upgrade().to_string()))
+        .await
+        .unwrap();
+
+    // once upgraded, control frames are still kept apart from the engine.io packets
+    ws.send(WsMessage::Ping(b"2".to_vec())).await.unwrap();
+    ws.send(WsMessage::Pong(b"3".to_vec())).await.unwrap();
+    ws.send(WsMessage::Text("4hi".to_string())).await.unwrap();
+    assert_eq!(
+        WsMessage::Pong(b"2".to_vec()),
+        ws.next().await.unwrap().unwrap()
+    );
+    assert_eq!(
+        WsMessage::Text("4hi".to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn upgrade_authentication() {
+    let server = TestServer::start();
+    let sid = server.open_session().await;
+    let url = format!("{}&token=revoked", server.websocket_url(&sid));
+    match tokio_tungstenite::connect_async(url).await {
+        Err(WsError::Http(res)) => assert_eq!(StatusCode::FORBIDDEN, res.status()),
+        res => panic!(
+            "expected a rejected upgrade, got {:?}",
+            res.map(|(_, res)| res)
+        ),
+    }
+    let url = format!("{}&token=valid", server.websocket_url(&sid));
+    assert!(tokio_tungstenite::connect_async(url).await.is_ok());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn websocket_handshake() {
+    let server = TestServer::start();
+    // a client connecting without polling first receives the Open packet of a new session
+    let url = format!("ws://{}/websocket?EIO=4&transport=websocket", server.addr);
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let open = match ws.next().await.unwrap().unwrap() {
+        WsMessage::Text(text) => Packet::try_from(text.as_str()).unwrap(),
+        msg => panic!("expected the Open packet, got {:?}", msg),
+    };
+    assert_eq!(&PacketType::Open, open.packet_type());
+
+    ws.send(WsMessage::Text("4hi".to_string())).await.unwrap();
+    assert_eq!(
+        WsMessage::Text("4hi".to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn websocket_max_message_size() {
+    let mut config = WebsocketConfig::default();
+    config.handshake.max_payload = 16;
+    let server = TestServer::with_websocket(config);
+    let url = format!("ws://{}/websocket?EIO=4&transport=websocket", server.addr);
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    // a message within the max payload is echoed
+    ws.send(WsMessage::Text("4hello".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        WsMessage::Text("4hello".to_string()),
+        ws.next().await.unwrap().unwrap()
+    );
+    // a larger one closes the connection without being processed
+    ws.send(WsMessage::Text(format!("4{}", "a".repeat(16))))
+        .await
+        .unwrap();
+    while let Some(Ok(msg)) = ws.next().await {
+        assert!(!msg.is_text(), "unexpected message {:?}", msg);
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn handshake_then_long_poll() {
+    let server = TestServer::start();
+    let base = format!("http://{}/?EIO=4&transport=polling", server.addr);
+
+    // the first GET carries no sid, and returns the Open packet of a new session
+    let (status, body) = request(Method::GET, base.clone(), "").await;
+    assert_eq!(StatusCode::OK, status);
+    let handshake: Value = serde_json::from_str(body.strip_prefix('0').unwrap()).unwrap();
+    let sid = handshake["sid"].as_str().unwrap();
+    let polling = format!("{}&sid={}", base, sid);
+
+    // the follow-up GET is held open until the echo of the posted message is queued
+    let poll = tokio::spawn(request(Method::GET, polling.clone(), ""));
+    tokio::task::yield_now().await;
+    let (status, _) = request(Method::POST, polling, "4hi").await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!((StatusCode::OK, "4hi".to_string()), poll.await.unwrap());
+
+    // another sid-less GET opens another session
+    let (_, body) = request(Method::GET, base, "").await;
+    let other: Value = serde_json::from_str(body.strip_prefix('0').unwrap()).unwrap();
+    assert_ne!(handshake["sid"], other["sid"]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn max_http_buffer_size() {
+    let server = TestServer::with_config(
+        PollingConfig {
+            max_http_buffer_size: 6,
+            ..PollingConfig::default()
+        },
+        WebsocketConfig::default(),
+    );
+    let sid = server.open_session().await;
+    let polling = format!(
+        "http://{}/?EIO=4&transport=polling&sid={}",
+        server.addr, sid
+    );
+
+    // a body at the limit is accepted
+    let (status, body) = request(Method::POST, polling.clone(), "4hello").await;
+    assert_eq!((StatusCode::OK, "ok".to_string()), (status, body));
+    let (status, body) = request(Method::GET, polling.clone(), "").await;
+    assert_eq!((StatusCode::OK, "4hello".to_string()), (status, body));
+
+    // one byte more is rejected before being parsed, and the session stays open
+    let (status, body) = request(Method::POST, polling.clone(), "4hello!").await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+    let error: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(3, error["code"]);
+    let (status, _) = request(Method::POST, polling.clone(), "4hi").await;
+    assert_eq!(StatusCode::OK, status);
+
+    // a chunked body has no Content-Length, and is rejected while being read
+    let (mut sender, body) = Body::channel();
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(polling)
+        .body(body)
+        .unwrap();
+    let client = async move {
+        sender.send_data("4hel".into()).await.unwrap();
+        sender.send_data("lo!".into()).await.unwrap();
+    };
+    let (res, _) = tokio::join!(Client::new().request(req), client);
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.unwrap().status());
+
+    server.stop().await;
+}